@@ -0,0 +1,168 @@
+use eyre::Result;
+use regex::Regex;
+
+use crate::{
+    authentication::{generate_token, Access},
+    store::{now_secs, Store, DAY_SECS},
+};
+
+const ADMIN_COMMAND_REGEX: &str = r"(?s)^!admin(?:\s+(.*))?$";
+
+/// Commands that only operators can use
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Create a new token, usable by the given number of users
+    NewToken(u32),
+    Ban(String),
+    Unban(String),
+    Stats,
+    /// Send a message to everyone who has ever chatted
+    Broadcast(String),
+    Help,
+}
+
+/// Pulls a user ID out of either a plain ID or a mention, like `<@123>`
+fn parse_user(s: &str) -> Option<String> {
+    let id = s
+        .trim()
+        .trim_start_matches("<@")
+        .trim_start_matches('!')
+        .trim_end_matches('>');
+
+    (!id.is_empty() && id.chars().all(|c| c.is_ascii_digit())).then(|| id.to_string())
+}
+
+impl AdminCommand {
+    pub fn read(s: &str) -> Option<AdminCommand> {
+        let admin_regex =
+            Regex::new(ADMIN_COMMAND_REGEX).expect("implementation error - invalid regex");
+        let cap = admin_regex.captures(s.trim())?;
+        let args = cap.get(1).map(|m| m.as_str().trim()).unwrap_or_default();
+
+        let (command, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let rest = rest.trim();
+
+        let command = match command {
+            "token" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                ["new"] => AdminCommand::NewToken(1),
+                ["new", uses] => uses
+                    .parse()
+                    .map(AdminCommand::NewToken)
+                    .unwrap_or(AdminCommand::Help),
+                _ => AdminCommand::Help,
+            },
+            "ban" => parse_user(rest).map_or(AdminCommand::Help, AdminCommand::Ban),
+            "unban" => parse_user(rest).map_or(AdminCommand::Help, AdminCommand::Unban),
+            "stats" => AdminCommand::Stats,
+            "broadcast" if !rest.is_empty() => AdminCommand::Broadcast(rest.to_string()),
+            _ => AdminCommand::Help,
+        };
+
+        Some(command)
+    }
+
+    /// Runs any command that only needs the store, returning the reply for the admin.
+    /// Broadcasts need to talk to Discord, so they're left to the caller.
+    pub async fn run(&self, store: &Store) -> Result<String> {
+        let reply = match self {
+            AdminCommand::NewToken(uses) => {
+                let token = generate_token();
+                store
+                    .add_tokens(std::slice::from_ref(&token), None, *uses)
+                    .await?;
+                format!("New token, usable by {uses} user(s): `{token}`")
+            }
+            AdminCommand::Ban(user) => {
+                store.set_access(user, Access::Ban).await?;
+                format!("Banned {user}")
+            }
+            AdminCommand::Unban(user) => {
+                if store.remove_access(user).await? {
+                    format!("Unbanned {user}")
+                } else {
+                    format!("{user} wasn't banned")
+                }
+            }
+            AdminCommand::Stats => {
+                let since = now_secs() - DAY_SECS;
+                format!(
+                    "Users: {}\nMessages in the last day: {}\nTokens used in the last day: {}",
+                    store.session_user_ids().await?.len(),
+                    store.all_messages_since(since).await?,
+                    store.all_tokens_since(since).await?,
+                )
+            }
+            AdminCommand::Broadcast(_) => "Broadcasts can only be sent from Discord".to_string(),
+            AdminCommand::Help => "Admin commands:
+- `!admin token new [uses]` Create a new token, usable by that many users (1 by default)
+- `!admin ban <user>` Stop a user from chatting
+- `!admin unban <user>` Let a banned user chat again
+- `!admin stats` Show how busy Gnome Chompski has been
+- `!admin broadcast <message>` Send a message to everyone who has chatted with Gnome Chompski"
+                .to_string(),
+        };
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_admin_commands() {
+        assert_eq!(AdminCommand::read("!chat hello"), None);
+        assert_eq!(AdminCommand::read("!administer"), None);
+        assert_eq!(AdminCommand::read("!admin"), Some(AdminCommand::Help));
+        assert_eq!(
+            AdminCommand::read("!admin token new"),
+            Some(AdminCommand::NewToken(1))
+        );
+        assert_eq!(
+            AdminCommand::read("!admin token new 30"),
+            Some(AdminCommand::NewToken(30))
+        );
+        assert_eq!(
+            AdminCommand::read("!admin ban <@!123>"),
+            Some(AdminCommand::Ban("123".to_string()))
+        );
+        assert_eq!(
+            AdminCommand::read("!admin ban bob"),
+            Some(AdminCommand::Help)
+        );
+        assert_eq!(
+            AdminCommand::read("!admin unban 123"),
+            Some(AdminCommand::Unban("123".to_string()))
+        );
+        assert_eq!(
+            AdminCommand::read("!admin stats"),
+            Some(AdminCommand::Stats)
+        );
+        assert_eq!(
+            AdminCommand::read("!admin broadcast Hello!\nBack soon."),
+            Some(AdminCommand::Broadcast("Hello!\nBack soon.".to_string()))
+        );
+        assert_eq!(
+            AdminCommand::read("!admin broadcast"),
+            Some(AdminCommand::Help)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ban_and_unban() {
+        let store = Store::in_memory().await.unwrap();
+
+        AdminCommand::Ban("123".to_string())
+            .run(&store)
+            .await
+            .unwrap();
+        assert_eq!(store.access("123").await.unwrap(), Some(Access::Ban));
+
+        AdminCommand::Unban("123".to_string())
+            .run(&store)
+            .await
+            .unwrap();
+        assert_eq!(store.access("123").await.unwrap(), None);
+    }
+}
@@ -1,14 +1,93 @@
 use eyre::Result;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use regex::Regex;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use strum::{Display, EnumString};
 
 use crate::store::Store;
 
 pub enum AuthenticationStrategy {
     NoAuthentication,
     TokenList(Store),
+    GuildRole(GuildRoles),
+    AccessList(AccessList),
+}
+
+/// Whether a user has been explicitly let in or kept out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, sqlx::Type)]
+#[strum(serialize_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Access {
+    Allow,
+    Ban,
+}
+
+/// Checks users against the allow and ban lists kept in the store, before falling back to another
+/// strategy. Banned users are always turned away, and allowed users are always let in. Everyone
+/// else is up to the fallback, or turned away if there isn't one.
+pub struct AccessList {
+    pub store: Store,
+    pub fallback: Option<Box<AuthenticationStrategy>>,
+}
+
+/// Lets in members of a Discord server who hold one of the given roles (or any member, if no
+/// roles are given). Membership is looked up by the Discord handler, and remembered here for a
+/// while so that we don't have to ask Discord about every message.
+pub struct GuildRoles {
+    pub guild_id: u64,
+    pub role_ids: Vec<u64>,
+    cache_for: Duration,
+    checked: Mutex<HashMap<String, (Instant, bool)>>,
+}
+
+impl GuildRoles {
+    pub fn new(guild_id: u64, role_ids: Vec<u64>, cache_for: Duration) -> Self {
+        Self {
+            guild_id,
+            role_ids,
+            cache_for,
+            checked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a member with the given roles should be let in
+    pub fn allows(&self, member_roles: &[u64]) -> bool {
+        self.role_ids.is_empty() || member_roles.iter().any(|r| self.role_ids.contains(r))
+    }
+
+    /// The result of the user's last check, if it was recent enough to still trust
+    pub fn cached(&self, user_id: &str) -> Option<bool> {
+        let checked = self.checked.lock().expect("poisoned guild role cache");
+        checked
+            .get(user_id)
+            .filter(|(at, _)| at.elapsed() < self.cache_for)
+            .map(|(_, allowed)| *allowed)
+    }
+
+    /// Remembers whether the user was let in
+    pub fn remember(&self, user_id: &str, allowed: bool) {
+        self.checked
+            .lock()
+            .expect("poisoned guild role cache")
+            .insert(user_id.to_string(), (Instant::now(), allowed));
+    }
 }
 
 const TOKEN_REGEX: &str = r"^!token\s+(.+)$";
+const GENERATED_TOKEN_LENGTH: usize = 24;
+
+/// Generates a new random token, using the OS's secure random number generator
+pub fn generate_token() -> String {
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
 
 pub enum AuthResult {
     /// Auth was successful
@@ -17,6 +96,12 @@ pub enum AuthResult {
     InvalidToken,
     /// Token was incorrectly provided
     MalformedTokenRequest,
+    /// User isn't in the right server, or doesn't have the right role
+    MissingRole,
+    /// User has been banned
+    Banned,
+    /// Only users on the allow list are let in, and this user isn't one of them
+    NotAllowed,
 }
 
 impl AuthenticationStrategy {
@@ -24,14 +109,46 @@ impl AuthenticationStrategy {
         match self {
             AuthenticationStrategy::NoAuthentication => false,
             AuthenticationStrategy::TokenList(_) => true,
+            AuthenticationStrategy::GuildRole(_) => true,
+            AuthenticationStrategy::AccessList(_) => true,
+        }
+    }
+
+    /// The server roles to check users against, if that's part of the strategy
+    pub fn guild_roles(&self) -> Option<&GuildRoles> {
+        match self {
+            AuthenticationStrategy::GuildRole(guild) => Some(guild),
+            AuthenticationStrategy::AccessList(AccessList {
+                fallback: Some(fallback),
+                ..
+            }) => fallback.guild_roles(),
+            _ => None,
         }
     }
 
-    /// Checks whether a user is authenticated,
-    pub async fn is_user_authenticated(&self, user_id: &str) -> Result<bool> {
+    /// Checks whether a user is authenticated. `has_role` is the result of looking up the user's
+    /// server roles, if that's just been done - otherwise the last remembered result is used.
+    pub async fn is_user_authenticated(
+        &self,
+        user_id: &str,
+        has_role: Option<bool>,
+    ) -> Result<bool> {
         match self {
             AuthenticationStrategy::NoAuthentication => Ok(true),
             AuthenticationStrategy::TokenList(store) => store.has_allocated_token(user_id).await,
+            AuthenticationStrategy::GuildRole(guild) => Ok(has_role
+                .or_else(|| guild.cached(user_id))
+                .unwrap_or_default()),
+            AuthenticationStrategy::AccessList(list) => match list.store.access(user_id).await? {
+                Some(Access::Ban) => Ok(false),
+                Some(Access::Allow) => Ok(true),
+                None => match &list.fallback {
+                    Some(fallback) => {
+                        Box::pin(fallback.is_user_authenticated(user_id, has_role)).await
+                    }
+                    None => Ok(false),
+                },
+            },
         }
     }
 
@@ -53,6 +170,101 @@ impl AuthenticationStrategy {
                     Ok(AuthResult::MalformedTokenRequest)
                 }
             }
+            // There's nothing the user can say to get the role
+            AuthenticationStrategy::GuildRole(_) => Ok(AuthResult::MissingRole),
+            AuthenticationStrategy::AccessList(list) => match list.store.access(user_id).await? {
+                Some(Access::Ban) => Ok(AuthResult::Banned),
+                Some(Access::Allow) => Ok(AuthResult::Success),
+                None => match &list.fallback {
+                    Some(fallback) => Box::pin(fallback.add_auth_for_new_user(user_id, msg)).await,
+                    None => Ok(AuthResult::NotAllowed),
+                },
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), GENERATED_TOKEN_LENGTH);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(token, generate_token());
+    }
+
+    #[tokio::test]
+    async fn test_access_list() {
+        let store = Store::in_memory().await.unwrap();
+        store.ensure_tokens(&["abc".to_string()]).await.unwrap();
+        store.set_access("1", Access::Allow).await.unwrap();
+        store.set_access("2", Access::Ban).await.unwrap();
+
+        let auth = AuthenticationStrategy::AccessList(AccessList {
+            store: store.clone(),
+            fallback: Some(Box::new(AuthenticationStrategy::TokenList(store.clone()))),
+        });
+        assert!(auth.is_user_authenticated("1", None).await.unwrap());
+        assert!(!auth.is_user_authenticated("3", None).await.unwrap());
+
+        // Banned users are kept out, even with a valid token..
+        assert!(matches!(
+            auth.add_auth_for_new_user("2", "!token abc").await.unwrap(),
+            AuthResult::Banned
+        ));
+        // ..which is left for someone else
+        assert!(matches!(
+            auth.add_auth_for_new_user("3", "!token abc").await.unwrap(),
+            AuthResult::Success
+        ));
+        assert!(auth.is_user_authenticated("3", None).await.unwrap());
+
+        store.set_access("3", Access::Ban).await.unwrap();
+        assert!(!auth.is_user_authenticated("3", None).await.unwrap());
+
+        let allowlist_only = AuthenticationStrategy::AccessList(AccessList {
+            store: store.clone(),
+            fallback: None,
+        });
+        assert!(allowlist_only
+            .is_user_authenticated("1", None)
+            .await
+            .unwrap());
+        assert!(matches!(
+            allowlist_only
+                .add_auth_for_new_user("4", "hello")
+                .await
+                .unwrap(),
+            AuthResult::NotAllowed
+        ));
+    }
+
+    #[test]
+    fn test_guild_roles() {
+        let guild = GuildRoles::new(1, vec![10, 20], Duration::from_secs(60));
+        assert!(guild.allows(&[5, 20]));
+        assert!(!guild.allows(&[5]));
+        assert!(GuildRoles::new(1, vec![], Duration::from_secs(60)).allows(&[]));
+
+        assert_eq!(guild.cached("123"), None);
+        guild.remember("123", true);
+        assert_eq!(guild.cached("123"), Some(true));
+
+        // Nothing is trusted if it can't be cached..
+        let guild = GuildRoles::new(1, vec![], Duration::ZERO);
+        guild.remember("123", true);
+        assert_eq!(guild.cached("123"), None);
+    }
+
+    #[tokio::test]
+    async fn test_guild_roles_without_caching() {
+        let auth = AuthenticationStrategy::GuildRole(GuildRoles::new(1, vec![], Duration::ZERO));
+
+        // ..but a fresh check still lets the user in
+        assert!(auth.is_user_authenticated("123", Some(true)).await.unwrap());
+        assert!(!auth.is_user_authenticated("123", None).await.unwrap());
+    }
+}
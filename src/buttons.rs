@@ -0,0 +1,71 @@
+use serenity::{builder::CreateComponents, model::application::component::ButtonStyle};
+
+use crate::model::FollowUp;
+
+/// The buttons that go under a message, depending on what sort of message it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buttons {
+    /// Under the bot's side of the conversation
+    Conversation,
+    /// Under the corrections to the user's messages
+    Correction,
+}
+
+impl Buttons {
+    fn follow_ups(self) -> &'static [FollowUp] {
+        match self {
+            Buttons::Conversation => &[FollowUp::Undo, FollowUp::Regenerate, FollowUp::Translate],
+            Buttons::Correction => &[FollowUp::Explain],
+        }
+    }
+
+    /// Adds the buttons to a message, as a single row
+    pub fn add(self, components: &mut CreateComponents) -> &mut CreateComponents {
+        components.create_action_row(|row| {
+            for follow_up in self.follow_ups() {
+                row.create_button(|button| {
+                    button
+                        .custom_id(follow_up)
+                        .label(label(*follow_up))
+                        .style(ButtonStyle::Secondary)
+                });
+            }
+            row
+        })
+    }
+}
+
+fn label(follow_up: FollowUp) -> &'static str {
+    match follow_up {
+        FollowUp::Undo => "Undo",
+        FollowUp::Regenerate => "Regenerate",
+        FollowUp::Translate => "Translate this",
+        FollowUp::Explain => "Explain correction",
+    }
+}
+
+/// Works out which button was clicked
+pub fn read_follow_up(custom_id: &str) -> Option<FollowUp> {
+    custom_id.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn test_every_follow_up_has_a_button() {
+        for follow_up in FollowUp::iter() {
+            assert!(
+                [Buttons::Conversation, Buttons::Correction]
+                    .iter()
+                    .any(|buttons| buttons.follow_ups().contains(&follow_up)),
+                "{follow_up} has no button"
+            );
+            assert_eq!(read_follow_up(&follow_up.to_string()), Some(follow_up));
+        }
+
+        assert_eq!(read_follow_up("something else"), None);
+    }
+}
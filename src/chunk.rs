@@ -0,0 +1,266 @@
+const CODE_FENCE: &str = "```";
+
+/// A paragraph of text, or a fenced code block
+#[derive(Debug)]
+enum Block<'a> {
+    Text(Vec<&'a str>),
+    Code {
+        opener: &'a str,
+        lines: Vec<&'a str>,
+    },
+}
+
+impl Block<'_> {
+    fn render(&self) -> String {
+        match self {
+            Block::Text(lines) => lines.join("\n"),
+            Block::Code { opener, lines } => fence(opener, lines),
+        }
+    }
+}
+
+fn fence(opener: &str, lines: &[&str]) -> String {
+    let mut out = opener.to_string();
+    for line in lines {
+        out.push('\n');
+        out.push_str(line);
+    }
+    out.push('\n');
+    out.push_str(CODE_FENCE);
+    out
+}
+
+fn len(s: &str) -> usize {
+    s.chars().count()
+}
+
+/// Splits a message up into paragraphs and code blocks
+fn blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = vec![];
+    let mut paragraph = vec![];
+    let mut code: Option<(&str, Vec<&str>)> = None;
+
+    for line in text.lines() {
+        let is_fence = line.trim_start().starts_with(CODE_FENCE);
+
+        if let Some((opener, mut lines)) = code.take() {
+            if is_fence {
+                blocks.push(Block::Code { opener, lines });
+            } else {
+                lines.push(line);
+                code = Some((opener, lines));
+            }
+        } else if is_fence {
+            if !paragraph.is_empty() {
+                blocks.push(Block::Text(std::mem::take(&mut paragraph)));
+            }
+            code = Some((line, vec![]));
+        } else if line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                blocks.push(Block::Text(std::mem::take(&mut paragraph)));
+            }
+        } else {
+            paragraph.push(line);
+        }
+    }
+
+    // An unterminated code block is closed off when rendered
+    if let Some((opener, lines)) = code {
+        blocks.push(Block::Code { opener, lines });
+    }
+    if !paragraph.is_empty() {
+        blocks.push(Block::Text(paragraph));
+    }
+
+    blocks
+}
+
+/// Splits a single line that is too long, preferring to break on whitespace
+fn split_line(line: &str, limit: usize) -> Vec<String> {
+    let mut out = vec![];
+    let mut rest = line;
+
+    while len(rest) > limit {
+        let window_end = rest
+            .char_indices()
+            .nth(limit)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let window = &rest[..window_end];
+
+        let split_at = match window.rfind(char::is_whitespace) {
+            Some(i) if i > 0 => i,
+            _ => window_end,
+        };
+
+        out.push(rest[..split_at].trim_end().to_string());
+        rest = rest[split_at..].trim_start();
+    }
+
+    if !rest.is_empty() {
+        out.push(rest.to_string());
+    }
+
+    out
+}
+
+/// Greedily packs lines into pieces of at most `limit` characters
+fn pack_lines<'a>(lines: impl IntoIterator<Item = &'a str>, limit: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut current = String::new();
+
+    for line in lines {
+        for part in split_line(line, limit) {
+            if !current.is_empty() && len(&current) + 1 + len(&part) > limit {
+                pieces.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&part);
+        }
+    }
+
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+
+    pieces
+}
+
+/// Splits a block that can't fit in a single message. Code blocks are split into several
+/// complete code blocks, so no message ever ends part-way through a fence.
+fn split_block(block: &Block, limit: usize) -> Vec<String> {
+    match block {
+        Block::Text(lines) => pack_lines(lines.iter().copied(), limit),
+        Block::Code { opener, lines } => {
+            // The fences, plus the newlines either side of the content
+            let overhead = len(&fence(opener, &[""]));
+            let available = limit.saturating_sub(overhead).max(1);
+
+            pack_lines(lines.iter().copied(), available)
+                .iter()
+                .map(|piece| fence(opener, &[piece]))
+                .collect()
+        }
+    }
+}
+
+/// Splits a message into parts of at most `limit` characters, breaking between paragraphs where
+/// possible, then between lines, and never inside a code block.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+
+    for block in blocks(text) {
+        let rendered = block.render();
+
+        if !current.is_empty() && len(&current) + 2 + len(&rendered) <= limit {
+            current.push_str("\n\n");
+            current.push_str(&rendered);
+            continue;
+        }
+
+        if !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        if len(&rendered) <= limit {
+            current = rendered;
+        } else {
+            let mut pieces = split_block(&block, limit);
+            current = pieces.pop().unwrap_or_default();
+            chunks.extend(pieces);
+        }
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_messages_are_untouched() {
+        assert_eq!(split_message("cześć!", 2000), vec!["cześć!"]);
+        assert_eq!(
+            split_message("one\n\ntwo\nthree", 2000),
+            vec!["one\n\ntwo\nthree"]
+        );
+        assert!(split_message("", 2000).is_empty());
+    }
+
+    #[test]
+    fn test_splits_on_paragraphs() {
+        let text = "aaaa aaaa\n\nbbbb bbbb\n\ncccc";
+        assert_eq!(
+            split_message(text, 20),
+            vec!["aaaa aaaa\n\nbbbb bbbb", "cccc"]
+        );
+    }
+
+    #[test]
+    fn test_splits_long_paragraphs_on_lines_then_words() {
+        let text = "aaaa\nbbbb\ncccc dddd eeee";
+        assert_eq!(
+            split_message(text, 10),
+            vec!["aaaa\nbbbb", "cccc dddd", "eeee"]
+        );
+    }
+
+    #[test]
+    fn test_never_splits_inside_code_blocks() {
+        let text =
+            "Here are the cases:\n\n```\nmianownik: kot\ndopełniacz: kota\ncelownik: kotu\n```";
+
+        let chunks = split_message(text, 40);
+        assert_eq!(
+            chunks,
+            vec![
+                "Here are the cases:",
+                "```\nmianownik: kot\ndopełniacz: kota\n```",
+                "```\ncelownik: kotu\n```",
+            ]
+        );
+        assert!(chunks.iter().all(|c| len(c) <= 40));
+    }
+
+    #[test]
+    fn test_code_blocks_fill_up_to_the_limit() {
+        let limit = 40;
+        let overhead = len(&fence(CODE_FENCE, &[""]));
+        let line = "a".repeat(limit - overhead);
+        let text = format!("```\n{line}\n{line}\n```");
+
+        let chunks = split_message(&text, limit);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|c| len(c) <= limit));
+        assert_eq!(len(&chunks[0]), limit);
+    }
+
+    #[test]
+    fn test_code_blocks_keep_their_language() {
+        let text = "```md\n| a | b |\n| c | d |\n```";
+        assert_eq!(
+            split_message(text, 20),
+            vec!["```md\n| a | b |\n```", "```md\n| c | d |\n```"]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_code_blocks_are_closed() {
+        assert_eq!(split_message("```\nfoo", 2000), vec!["```\nfoo\n```"]);
+    }
+
+    #[test]
+    fn test_counts_characters_not_bytes() {
+        let text = "żółć żółć";
+        assert_eq!(split_message(text, 9), vec!["żółć żółć"]);
+        assert_eq!(split_message(text, 8), vec!["żółć", "żółć"]);
+    }
+}
@@ -2,41 +2,574 @@ use eyre::Result;
 use log::{error, warn};
 use serenity::{
     async_trait,
-    framework::StandardFramework,
-    futures::lock::Mutex,
+    futures::{future::BoxFuture, lock::Mutex},
+    http::StatusCode,
     model::{
-        prelude::{Channel, Message, Ready, UserId},
+        application::{
+            command::Command as SlashCommandList,
+            interaction::{
+                application_command::ApplicationCommandInteraction,
+                message_component::MessageComponentInteraction, Interaction,
+                InteractionResponseType,
+            },
+        },
+        prelude::{ChannelId, GuildId, Message, Ready, UserId},
         user::User,
     },
     prelude::{Context, EventHandler, GatewayIntents},
     Client,
 };
 use std::{collections::HashMap, env, sync::Arc, time::Duration};
+use tokio::{sync::watch, time::error::Elapsed};
 
 use crate::{
-    authentication::{AuthResult, AuthenticationStrategy},
-    model::{MessageReply, TeachBot},
+    admin::AdminCommand,
+    authentication::{AuthResult, AuthenticationStrategy, GuildRoles},
+    buttons::{self, Buttons},
+    chunk::split_message,
+    gpt::{BackendError, ChatBackend},
+    model::{Command, FollowUp, Level, MessageReply, TeachBot},
+    quota::Limits,
+    slash,
+    store::{now_secs, Store},
+    usage::{self, Pricing},
 };
 
 const DISCORD_API_TOKEN: &str = "DISCORD_API_TOKEN";
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
+/// The least time we give the bot to handle a message, before giving up on it
+const HANDLE_TIMEOUT_SECS: u64 = 60;
+const SHY_REPLY: &str = "I'm shy, so I don't talk in public! Message me directly to chat :)";
+/// Tokens shouldn't be pasted in public, so authenticating has to happen over DM
+const AUTHENTICATE_OVER_DM: &str =
+    "You're not authenticated yet. Message me directly to find out how!";
+/// Discord rate-limits message edits, so streamed replies are updated at most this often
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+
+/// Waits until no more updates are coming
+async fn wait_until_closed(updates: &mut watch::Receiver<String>) {
+    while updates.changed().await.is_ok() {}
+}
+
+/// Shows a reply while it is being streamed in, by posting it as soon as the first piece arrives
+/// and then editing it as more turns up. Returns the posted message, if anything was posted.
+async fn stream_reply(
+    ctx: Context,
+    msg: Message,
+    mut updates: watch::Receiver<String>,
+) -> Option<Message> {
+    let mut posted: Option<Message> = None;
+
+    while updates.changed().await.is_ok() {
+        let text = updates.borrow_and_update().clone();
+
+        // Anything too long for one message gets split up once the reply is complete
+        if !text.trim().is_empty() && text.chars().count() <= DISCORD_MESSAGE_LIMIT {
+            let result = match posted.as_mut() {
+                Some(posted) => posted.edit(&ctx.http, |m| m.content(&text)).await,
+                None => msg
+                    .reply(&ctx.http, &text)
+                    .await
+                    .map(|message| posted = Some(message)),
+            };
+
+            if let Err(e) = result {
+                error!("Error while streaming reply: {e:?}");
+            }
+        }
+
+        // Hold off before editing again, unless the reply finishes in the meantime
+        if tokio::time::timeout(STREAM_EDIT_INTERVAL, wait_until_closed(&mut updates))
+            .await
+            .is_ok()
+        {
+            break;
+        }
+    }
+
+    posted
+}
+
+/// Splits a reply into parts short enough for Discord, each with the buttons to go under it.
+/// Buttons only go on the last part, so they sit under the whole reply.
+fn parts_with_buttons(
+    reply: &str,
+    buttons: Option<Buttons>,
+) -> impl Iterator<Item = (String, Option<Buttons>)> {
+    let parts = split_message(reply, DISCORD_MESSAGE_LIMIT);
+    let last = parts.len().saturating_sub(1);
+
+    parts
+        .into_iter()
+        .enumerate()
+        .map(move |(i, part)| (part, buttons.filter(|_| i == last)))
+}
+
+/// Replaces a streamed message with the complete reply, splitting it up if it's too long. Any
+/// buttons go on the last part.
+async fn finish_streamed_reply(
+    ctx: &Context,
+    posted: &mut Message,
+    reply: &str,
+    buttons: Option<Buttons>,
+) -> serenity::Result<()> {
+    for (i, (part, buttons)) in parts_with_buttons(reply, buttons).enumerate() {
+        if i == 0 {
+            if posted.content != part || buttons.is_some() {
+                posted
+                    .edit(&ctx.http, |m| {
+                        if let Some(buttons) = buttons {
+                            m.components(|c| buttons.add(c));
+                        }
+                        m.content(part)
+                    })
+                    .await?;
+            }
+        } else {
+            posted
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    if let Some(buttons) = buttons {
+                        m.components(|c| buttons.add(c));
+                    }
+                    m.content(part)
+                })
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Explains to the user what went wrong, as best we can
+fn error_reply(e: &eyre::Report) -> MessageReply {
+    let explanation = match e.downcast_ref::<BackendError>() {
+        Some(BackendError::Auth(_)) => {
+            "Gnome Chompski isn't allowed to talk to ChatGPT right now. Please let whoever runs him know that his API token needs checking."
+        }
+        Some(BackendError::RateLimited { .. }) => {
+            "ChatGPT says we're talking too much. Please wait a little while and then try again."
+        }
+        Some(BackendError::Overloaded { .. }) => {
+            "ChatGPT is overloaded at the moment. Please try again in a few minutes."
+        }
+        Some(BackendError::Timeout) => {
+            "ChatGPT took too long to reply. Please write your reply again."
+        }
+        Some(BackendError::Unreachable(_)) => {
+            "Gnome Chompski couldn't reach ChatGPT. Please try again in a few minutes."
+        }
+        Some(BackendError::BadResponse(_)) | None => {
+            "Something went wrong whilst communicating with Gnome Chompski. Please restate your reply and try again."
+        }
+    };
+
+    MessageReply::reply(format!(
+        "I'm sorry, I don't understand :(\n\n_{explanation}_"
+    ))
+}
+
+/// Replies to the given message, splitting the reply over several messages if it's too long for
+/// Discord to accept in one go. Any buttons go on the last part.
+async fn send_reply(
+    ctx: &Context,
+    msg: &Message,
+    reply: &str,
+    buttons: Option<Buttons>,
+) -> serenity::Result<()> {
+    for (i, (part, buttons)) in parts_with_buttons(reply, buttons).enumerate() {
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                // Like `Message::reply`, without pinging the user
+                if i == 0 {
+                    m.reference_message(msg)
+                        .allowed_mentions(|a| a.replied_user(false));
+                }
+                if let Some(buttons) = buttons {
+                    m.components(|c| buttons.add(c));
+                }
+                m.content(part)
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Looks up whether the user is in the server with one of the right roles. Members are taken from
+/// the cache where possible, and fetched from Discord otherwise.
+async fn check_guild_roles(ctx: &Context, guild: &GuildRoles, user_id: UserId) -> Result<bool> {
+    let guild_id = GuildId(guild.guild_id);
+
+    let member = match ctx.cache.member(guild_id, user_id) {
+        Some(member) => member,
+        None => match ctx.http.get_member(guild_id.0, user_id.0).await {
+            Ok(member) => member,
+            Err(serenity::Error::Http(e)) if e.status_code() == Some(StatusCode::NOT_FOUND) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    let roles: Vec<_> = member.roles.iter().map(|role| role.0).collect();
+    Ok(guild.allows(&roles))
+}
+
+/// A single session's bot. Each one sits behind its own lock, so that different sessions can be
+/// served in parallel while messages in the same session are handled one at a time. The bot is
+/// `None` until it has been loaded for the first time.
+type BotSlot = Arc<Mutex<Option<TeachBot>>>;
+
+/// Server-channel mode, where learners mention the bot in a channel to get a thread of their own
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadSettings {
+    pub channel_id: ChannelId,
+    /// Whether threads are only visible to the learner (and the server's moderators)
+    pub private: bool,
+}
+
+/// How the bot behaves, as configured at startup
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub default_level: Level,
+    /// Temperature for `!retry` and the Regenerate button
+    pub retry_temperature: Option<f32>,
+    pub pricing: Pricing,
+    pub limits: Limits,
+    pub admin_ids: Vec<UserId>,
+    pub threads: Option<ThreadSettings>,
+}
+
+/// Sessions are keyed by user for DMs, and by thread for threads
+fn thread_session(thread_id: ChannelId) -> String {
+    format!("thread:{}", thread_id.0)
+}
 
 struct Handler {
-    state: Arc<Mutex<HashMap<UserId, TeachBot>>>,
+    state: Mutex<HashMap<String, BotSlot>>,
     auth_strategy: AuthenticationStrategy,
+    backend: Arc<dyn ChatBackend>,
+    store: Store,
+    settings: Settings,
 }
 
 impl Handler {
-    fn new(auth_strategy: AuthenticationStrategy) -> Self {
+    fn new(
+        auth_strategy: AuthenticationStrategy,
+        backend: Arc<dyn ChatBackend>,
+        store: Store,
+        settings: Settings,
+    ) -> Self {
         Self {
-            state: Arc::new(Mutex::new(HashMap::new())),
+            state: Mutex::new(HashMap::new()),
             auth_strategy,
+            backend,
+            store,
+            settings,
         }
     }
 
+    /// Runs a command for one of the operators
+    async fn admin_reply(&self, ctx: &Context, command: AdminCommand) -> Result<String> {
+        let AdminCommand::Broadcast(message) = command else {
+            return command.run(&self.store).await;
+        };
+
+        let mut sent = 0;
+        let mut failed = 0;
+
+        for user_id in self.store.session_user_ids().await? {
+            let result = match user_id.parse() {
+                Ok(id) => match UserId(id).create_dm_channel(&ctx.http).await {
+                    Ok(channel) => channel.say(&ctx.http, &message).await.map(|_| ()),
+                    Err(e) => Err(e),
+                },
+                Err(_) => continue,
+            };
+
+            match result {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    error!("Could not send broadcast to user {user_id}: {e:?}");
+                    failed += 1;
+                }
+            }
+        }
+
+        Ok(format!(
+            "Sent to {sent} user(s), and failed to send to {failed}"
+        ))
+    }
+
+    /// Checks the user is within their quota, and counts this message towards it. Returns why
+    /// they've been turned away if not.
+    async fn take_quota(&self, user_id: &str) -> Result<Option<&'static str>> {
+        let limits = &self.settings.limits;
+        if !limits.is_unlimited() {
+            if let Some(exceeded) = limits.check(&self.store, user_id, now_secs()).await? {
+                warn!("User {user_id} is over their quota: {exceeded:?}");
+                return Ok(Some(exceeded.explanation()));
+            }
+        }
+
+        self.store.record_message(user_id).await?;
+        Ok(None)
+    }
+
+    /// Summarises how many tokens the user has used so far
+    async fn usage_reply(&self, user_id: &str) -> String {
+        match self.store.usage_totals(Some(user_id)).await {
+            Ok(totals) => format!(
+                "```\n{}\n```",
+                usage::report(&totals, &self.settings.pricing)
+            ),
+            Err(e) => {
+                error!("Could not fetch usage for user {user_id}: {e:?}");
+                "Sorry, I couldn't work out your usage right now.".to_string()
+            }
+        }
+    }
+
+    /// How long to give the bot to handle a message. Handling may summarise the conversation
+    /// before replying, so there's room for two requests to run out of retries, and for their
+    /// errors to be explained to the user.
+    fn handle_timeout(&self) -> Duration {
+        let timeout = Duration::from_secs(HANDLE_TIMEOUT_SECS);
+        self.backend
+            .max_request_duration()
+            .map_or(timeout, |max| (max * 2).max(timeout))
+    }
+
+    /// Fetches the slot for this session's bot. The map is only locked for as long as the lookup.
+    async fn bot_slot(&self, session_key: &str) -> BotSlot {
+        self.state
+            .lock()
+            .await
+            .entry(session_key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Loads the session's bot, picking up where it left off if it's been used before
+    async fn load_bot(&self, ctx: &Context, channel_id: ChannelId, session_key: &str) -> TeachBot {
+        let bot = match self.store.load_session(session_key).await {
+            Ok(Some(session)) => TeachBot::from_session(self.backend.clone(), session),
+            Ok(None) => {
+                if let Err(e) = channel_id
+                    .say(
+                        &ctx.http,
+                        "_This is your first message of the session. Did Gnome Chompski just wake up?_",
+                    )
+                    .await
+                {
+                    error!("Error while sending reply: {e:?}");
+                }
+                TeachBot::new(self.backend.clone(), self.settings.default_level)
+            }
+            Err(e) => {
+                error!("Could not load session {session_key}: {e:?}");
+                TeachBot::new(self.backend.clone(), self.settings.default_level)
+            }
+        };
+
+        bot.with_retry_temperature(self.settings.retry_temperature)
+    }
+
+    /// Wraps up after the bot has handled something: works out what to tell the user if it went
+    /// wrong, and saves the session and usage
+    async fn finish_handling(
+        &self,
+        session_key: &str,
+        user_id: &str,
+        state: &TeachBot,
+        result: Result<Result<MessageReply>, Elapsed>,
+    ) -> MessageReply {
+        let message_and_reply = match result {
+            Ok(maybe_msg) => match maybe_msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Error while interacting with bot: {e:?}");
+                    error_reply(&e)
+                }
+            },
+            Err(_) => MessageReply::reply(
+                "...I'm sorry, I wasn't paying attention. What were we talking about?
+
+_ChatGPT request timed out. Please write your reply again._
+",
+            ),
+        };
+
+        if let Err(e) = self.store.save_session(session_key, &state.session()).await {
+            error!("Could not save session {session_key}: {e:?}");
+        }
+
+        for (kind, usage) in &message_and_reply.usage {
+            if let Err(e) = self.store.record_usage(user_id, *kind, *usage).await {
+                error!("Could not record usage for user {user_id}: {e:?}");
+            }
+        }
+
+        message_and_reply
+    }
+
+    /// Hands the session's bot to `handle`, loading it first if need be, then wraps up as in
+    /// `finish_handling`. Holding the bot's lock until we're done means that messages in the same
+    /// session are handled in order, without holding up anyone else. Each request to the backend
+    /// has its own timeout and retries, but we give up altogether if the whole thing takes too
+    /// long.
+    async fn with_bot<F>(
+        &self,
+        ctx: &Context,
+        channel_id: ChannelId,
+        session_key: &str,
+        user_id: &str,
+        handle: F,
+    ) -> MessageReply
+    where
+        F: for<'a> FnOnce(&'a mut TeachBot) -> BoxFuture<'a, Result<MessageReply>>,
+    {
+        let slot = self.bot_slot(session_key).await;
+        let mut bot = slot.lock().await;
+
+        if bot.is_none() {
+            *bot = Some(self.load_bot(ctx, channel_id, session_key).await);
+        }
+        let state = bot.as_mut().expect("bot was just loaded");
+
+        let result = tokio::time::timeout(self.handle_timeout(), handle(state)).await;
+        self.finish_handling(session_key, user_id, state, result)
+            .await
+    }
+
+    /// Works out which session a message in a server belongs to, if any. Learners chat in their
+    /// own threads, and get one by mentioning the bot in the thread channel. We see every message
+    /// in the server, so nothing is asked of Discord unless the message is for us.
+    async fn guild_session(&self, ctx: &Context, msg: &Message) -> Result<Option<String>> {
+        let Some(threads) = self.settings.threads else {
+            msg.reply(&ctx.http, SHY_REPLY).await?;
+            return Ok(None);
+        };
+
+        // Threads are only for the learner they were started for
+        if let Some(owner) = self
+            .store
+            .thread_owner(&msg.channel_id.0.to_string())
+            .await?
+        {
+            let is_owner = owner == msg.author.id.0.to_string();
+            return Ok(is_owner.then(|| thread_session(msg.channel_id)));
+        }
+
+        if !msg.mentions_user_id(ctx.cache.current_user_id()) {
+            return Ok(None);
+        }
+
+        if msg.channel_id == threads.channel_id {
+            self.open_thread(ctx, msg, threads).await?;
+        } else {
+            msg.reply(
+                &ctx.http,
+                format!("Come and find me in <#{}>!", threads.channel_id.0),
+            )
+            .await?;
+        }
+
+        Ok(None)
+    }
+
+    /// Starts a thread for the learner to chat in, unless they've already got one
+    async fn open_thread(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        threads: ThreadSettings,
+    ) -> Result<()> {
+        let user_id = msg.author.id.0.to_string();
+
+        if self.auth_strategy.auth_required() && !self.is_authenticated(ctx, &msg.author).await? {
+            msg.reply(&ctx.http, AUTHENTICATE_OVER_DM).await?;
+            return Ok(());
+        }
+
+        if let Some(thread_id) = self.store.user_thread(&user_id).await? {
+            let thread_id: u64 = thread_id.parse()?;
+
+            // The thread may have been deleted since, in which case they get a new one
+            if ctx.http.get_channel(thread_id).await.is_ok() {
+                msg.reply(
+                    &ctx.http,
+                    format!("We're already chatting in <#{thread_id}>!"),
+                )
+                .await?;
+                return Ok(());
+            }
+        }
+
+        let name = format!("{} & Gnome Chompski", msg.author.name);
+        let thread = if threads.private {
+            let thread = threads
+                .channel_id
+                .create_private_thread(&ctx.http, |t| t.name(&name))
+                .await?;
+            thread
+                .id
+                .add_thread_member(&ctx.http, msg.author.id)
+                .await?;
+            thread
+        } else {
+            threads
+                .channel_id
+                .create_public_thread(&ctx.http, msg.id, |t| t.name(&name))
+                .await?
+        };
+
+        self.store
+            .add_thread(&thread.id.0.to_string(), &user_id)
+            .await?;
+        warn!(
+            "Started thread {} for user {} ({user_id})",
+            thread.id.0, msg.author.name
+        );
+
+        thread
+            .id
+            .say(
+                &ctx.http,
+                format!(
+                    "Hi <@{}>! This is our own thread to chat in. Start a conversation with `!chat <topic>`, or see what else I can do with `!help`.",
+                    msg.author.id.0
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Checks whether the user is let in, without asking them for anything
+    async fn is_authenticated(&self, ctx: &Context, user: &User) -> Result<bool> {
+        let user_id = user.id.0.to_string();
+
+        // Server roles are looked up here, where we can talk to Discord
+        let mut has_role = None;
+        if let Some(guild) = self.auth_strategy.guild_roles() {
+            if guild.cached(&user_id).is_none() {
+                let allowed = check_guild_roles(ctx, guild, user.id).await?;
+                guild.remember(&user_id, allowed);
+                has_role = Some(allowed);
+            }
+        }
+
+        self.auth_strategy
+            .is_user_authenticated(&user_id, has_role)
+            .await
+    }
+
     async fn authenticate_user(&self, user: &User, ctx: &Context, msg: &Message) -> Result<bool> {
         let user_id = user.id.0.to_string();
 
-        if self.auth_strategy.is_user_authenticated(&user_id).await? {
+        if self.is_authenticated(ctx, user).await? {
             return Ok(true);
         }
 
@@ -57,7 +590,7 @@ impl Handler {
                     "User {} ({user_id}) provided an invalid token: {}",
                     user.name, msg.content
                 );
-                "Unfortunately, your token appears to be invalid or has already been used before.\n\nAre you sure you entered it correctly?"
+                "Unfortunately, your token appears to be invalid, expired or already used up.\n\nAre you sure you entered it correctly?"
             }
             AuthResult::MalformedTokenRequest => {
                 warn!(
@@ -66,6 +599,27 @@ impl Handler {
                 );
                 "Hey there!\n\nUnfortunately, you are not authenticated yet. Please paste in your authentication token in the following format:\n\n`!token YOUR_TOKEN`"
             }
+            AuthResult::MissingRole => {
+                warn!(
+                    "User {} ({user_id}) wrote {}, but doesn't have the right role",
+                    user.name, msg.content
+                );
+                "Hey there!\n\nUnfortunately, Gnome Chompski only talks to members of his server with the right role. If you think you should have it, please ask whoever runs the server!"
+            }
+            AuthResult::Banned => {
+                warn!(
+                    "Banned user {} ({user_id}) wrote {}",
+                    user.name, msg.content
+                );
+                "Sorry, Gnome Chompski isn't talking to you any more."
+            }
+            AuthResult::NotAllowed => {
+                warn!(
+                    "User {} ({user_id}) wrote {}, but isn't on the allow list",
+                    user.name, msg.content
+                );
+                "Hey there!\n\nUnfortunately, Gnome Chompski only talks to people he's been introduced to. Please ask whoever runs him to add you!"
+            }
         };
 
         msg.reply(&ctx.http, reply).await?;
@@ -74,6 +628,295 @@ impl Handler {
     }
 }
 
+/// Slash commands and button clicks are different types, but are answered in the same ways
+#[async_trait]
+trait AnyInteraction: Sync {
+    fn user(&self) -> &User;
+    fn guild_id(&self) -> Option<GuildId>;
+    fn channel_id(&self) -> ChannelId;
+
+    /// Responds with a message that only the user can see
+    async fn respond_privately(&self, ctx: &Context, content: &str) -> serenity::Result<()>;
+    /// Lets Discord know that a response is coming, since they can take a while
+    async fn defer_response(&self, ctx: &Context, private: bool) -> serenity::Result<()>;
+    async fn edit_response(
+        &self,
+        ctx: &Context,
+        content: &str,
+        buttons: Option<Buttons>,
+    ) -> serenity::Result<()>;
+    async fn delete_response(&self, ctx: &Context) -> serenity::Result<()>;
+    async fn follow_up(
+        &self,
+        ctx: &Context,
+        content: &str,
+        private: bool,
+        buttons: Option<Buttons>,
+    ) -> serenity::Result<()>;
+
+    /// Fills in the deferred response. The conversation is posted as the response, and anything
+    /// else (help, corrections, errors) is only shown to the user.
+    async fn send_reply(
+        &self,
+        ctx: &Context,
+        message_and_reply: MessageReply,
+    ) -> serenity::Result<()> {
+        let follow_ups = message_and_reply.follow_ups;
+
+        match message_and_reply.channel {
+            Some(message) => {
+                let buttons = follow_ups.then_some(Buttons::Conversation);
+                for (i, (part, buttons)) in parts_with_buttons(&message, buttons).enumerate() {
+                    if i == 0 {
+                        self.edit_response(ctx, &part, buttons).await?;
+                    } else {
+                        self.follow_up(ctx, &part, false, buttons).await?;
+                    }
+                }
+            }
+            // There's nothing for everyone to see, so don't leave the response hanging around
+            None => self.delete_response(ctx).await?,
+        }
+
+        if let Some(reply) = message_and_reply.reply {
+            let buttons = follow_ups.then_some(Buttons::Correction);
+            for (part, buttons) in parts_with_buttons(&reply, buttons) {
+                self.follow_up(ctx, &part, true, buttons).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+macro_rules! impl_any_interaction {
+    ($interaction:ty) => {
+        #[async_trait]
+        impl AnyInteraction for $interaction {
+            fn user(&self) -> &User {
+                &self.user
+            }
+
+            fn guild_id(&self) -> Option<GuildId> {
+                self.guild_id
+            }
+
+            fn channel_id(&self) -> ChannelId {
+                self.channel_id
+            }
+
+            async fn respond_privately(
+                &self,
+                ctx: &Context,
+                content: &str,
+            ) -> serenity::Result<()> {
+                self.create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|d| d.content(content).ephemeral(true))
+                })
+                .await
+            }
+
+            async fn defer_response(&self, ctx: &Context, private: bool) -> serenity::Result<()> {
+                self.create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                        .interaction_response_data(|d| d.ephemeral(private))
+                })
+                .await
+            }
+
+            async fn edit_response(
+                &self,
+                ctx: &Context,
+                content: &str,
+                buttons: Option<Buttons>,
+            ) -> serenity::Result<()> {
+                self.edit_original_interaction_response(&ctx.http, |r| {
+                    if let Some(buttons) = buttons {
+                        r.components(|c| buttons.add(c));
+                    }
+                    r.content(content)
+                })
+                .await
+                .map(|_| ())
+            }
+
+            async fn delete_response(&self, ctx: &Context) -> serenity::Result<()> {
+                self.delete_original_interaction_response(&ctx.http).await
+            }
+
+            async fn follow_up(
+                &self,
+                ctx: &Context,
+                content: &str,
+                private: bool,
+                buttons: Option<Buttons>,
+            ) -> serenity::Result<()> {
+                self.create_followup_message(&ctx.http, |f| {
+                    if let Some(buttons) = buttons {
+                        f.components(|c| buttons.add(c));
+                    }
+                    f.content(content).ephemeral(private)
+                })
+                .await
+                .map(|_| ())
+            }
+        }
+    };
+}
+
+impl_any_interaction!(ApplicationCommandInteraction);
+impl_any_interaction!(MessageComponentInteraction);
+
+impl Handler {
+    /// Works out which session an interaction belongs to, and checks the user is let in. Tells
+    /// the user why not, if they can't use the bot here.
+    async fn interaction_session(
+        &self,
+        ctx: &Context,
+        interaction: &impl AnyInteraction,
+    ) -> Result<Option<String>> {
+        let user = interaction.user();
+        let user_id = user.id.0.to_string();
+
+        // Outside of DMs, we only talk in learners' own threads
+        let session_key = if interaction.guild_id().is_none() {
+            user_id.clone()
+        } else {
+            let thread_id = interaction.channel_id().0.to_string();
+            match self.store.thread_owner(&thread_id).await? {
+                Some(owner) if owner == user_id => thread_session(interaction.channel_id()),
+                _ => {
+                    interaction.respond_privately(ctx, SHY_REPLY).await?;
+                    return Ok(None);
+                }
+            }
+        };
+
+        if self.auth_strategy.auth_required() && !self.is_authenticated(ctx, user).await? {
+            interaction
+                .respond_privately(
+                    ctx,
+                    "You're not authenticated yet. Send me a message to find out how!",
+                )
+                .await?;
+            return Ok(None);
+        }
+
+        Ok(Some(session_key))
+    }
+
+    /// Counts the interaction towards the user's quota, telling them if they're over it
+    async fn interaction_quota(
+        &self,
+        ctx: &Context,
+        interaction: &impl AnyInteraction,
+    ) -> Result<bool> {
+        let user_id = interaction.user().id.0.to_string();
+
+        match self.take_quota(&user_id).await {
+            Ok(None) => Ok(true),
+            Ok(Some(explanation)) => {
+                interaction
+                    .respond_privately(ctx, &format!("_{explanation}_"))
+                    .await?;
+                Ok(false)
+            }
+            // As with messages, nobody is turned away just because we couldn't check
+            Err(e) => {
+                error!("Could not check quota for user {user_id}: {e:?}");
+                Ok(true)
+            }
+        }
+    }
+
+    /// Handles a slash command in much the same way as a message
+    async fn slash_command(
+        &self,
+        ctx: &Context,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        let Some(session_key) = self.interaction_session(ctx, interaction).await? else {
+            return Ok(());
+        };
+
+        let user = &interaction.user;
+        let user_id = user.id.0.to_string();
+
+        let command = slash::read_command(interaction);
+        warn!(
+            "User {} ({user_id}) used the slash command {command:?}",
+            user.name
+        );
+
+        if command == Command::Usage {
+            interaction
+                .respond_privately(ctx, &self.usage_reply(&user_id).await)
+                .await?;
+            return Ok(());
+        }
+
+        if !self.interaction_quota(ctx, interaction).await? {
+            return Ok(());
+        }
+
+        interaction.defer_response(ctx, false).await?;
+
+        let message_and_reply = self
+            .with_bot(ctx, interaction.channel_id, &session_key, &user_id, |bot| {
+                Box::pin(async move { bot.handle_command(command, &|_| {}).await })
+            })
+            .await;
+
+        interaction.send_reply(ctx, message_and_reply).await?;
+
+        Ok(())
+    }
+
+    /// Handles a click on one of the buttons under a reply
+    async fn button_click(
+        &self,
+        ctx: &Context,
+        interaction: &MessageComponentInteraction,
+    ) -> Result<()> {
+        let Some(follow_up) = buttons::read_follow_up(&interaction.data.custom_id) else {
+            warn!("Unknown button {}", interaction.data.custom_id);
+            return Ok(());
+        };
+
+        let Some(session_key) = self.interaction_session(ctx, interaction).await? else {
+            return Ok(());
+        };
+
+        let user = &interaction.user;
+        let user_id = user.id.0.to_string();
+        warn!(
+            "User {} ({user_id}) clicked the {follow_up} button",
+            user.name
+        );
+
+        if !self.interaction_quota(ctx, interaction).await? {
+            return Ok(());
+        }
+
+        // A regenerated reply carries on the conversation, so everyone can see it
+        interaction
+            .defer_response(ctx, follow_up != FollowUp::Regenerate)
+            .await?;
+
+        let message = interaction.message.content.clone();
+        let message_and_reply = self
+            .with_bot(ctx, interaction.channel_id, &session_key, &user_id, |bot| {
+                Box::pin(async move { bot.handle_follow_up(follow_up, &message, &|_| {}).await })
+            })
+            .await;
+
+        interaction.send_reply(ctx, message_and_reply).await?;
+
+        Ok(())
+    }
+}
+
 macro_rules! just_log_error {
     ($context:tt, $x:expr) => {{
         match $x {
@@ -94,117 +937,208 @@ impl EventHandler for Handler {
             return;
         }
 
-        // We don't reply on public / non-private channels
-        match msg.channel(&ctx.http).await {
-            Ok(channel) => {
-                if !matches!(channel, Channel::Private(_)) {
+        // Work out whose conversation this is. DMs are with the user, and threads (in
+        // server-channel mode) are with whoever they were started for.
+        let (session_key, in_server) = if msg.guild_id.is_none() {
+            (msg.author.id.0.to_string(), false)
+        } else {
+            match just_log_error!(
+                "handling server message",
+                self.guild_session(&ctx, &msg).await
+            ) {
+                Some(session_key) => (session_key, true),
+                None => return,
+            }
+        };
+
+        // Operators can run admin commands, whether or not they'd otherwise be let in. Their
+        // replies can include tokens, so they only work over DM.
+        if !in_server && self.settings.admin_ids.contains(&msg.author.id) {
+            if let Some(command) = AdminCommand::read(&msg.content) {
+                warn!(
+                    "Admin {} ({}) ran {command:?}",
+                    msg.author.name, msg.author.id.0
+                );
+                let reply = match self.admin_reply(&ctx, command).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        error!("Error while running admin command: {e:?}");
+                        format!("That didn't work: {e}")
+                    }
+                };
+                just_log_error!("sending reply", send_reply(&ctx, &msg, &reply, None).await);
+                return;
+            }
+        }
+
+        // Authenticate if necessary
+        if self.auth_strategy.auth_required() {
+            let authenticated = if in_server {
+                match self.is_authenticated(&ctx, &msg.author).await {
+                    Ok(false) => {
+                        just_log_error!(
+                            "sending reply",
+                            msg.reply(&ctx.http, AUTHENTICATE_OVER_DM).await
+                        );
+                        return;
+                    }
+                    result => result,
+                }
+            } else {
+                self.authenticate_user(&msg.author, &ctx, &msg).await
+            };
+
+            match authenticated {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    error!("Could not authenticate user {}: {e:?}", msg.author.id.0);
                     just_log_error!(
                         "sending reply",
                         msg.reply(
                             &ctx.http,
-                            "I'm shy, so I don't talk in public! Message me directly to chat :)",
+                            "_Gnome Chompski couldn't check who you are right now. Please try again in a minute._",
                         )
                         .await
                     );
                     return;
                 }
             }
-            Err(why) => {
-                error!("Could not fetch channel due to {why:?}");
-                return;
-            }
         }
 
-        // Authenticate if necessary
-        if self.auth_strategy.auth_required()
-            && !self
-                .authenticate_user(&msg.author, &ctx, &msg)
-                .await
-                .unwrap()
-        {
-            return;
-        };
-
         warn!(
             "User {} ({}) is chatting with Gnome Chompski.",
             msg.author.name, msg.author.id.0
         );
 
-        // Find the relevant bot for this user
-        let mut all_bots = self.state.lock().await;
+        let user_id = msg.author.id.0.to_string();
 
-        let state = if let Some(state) = all_bots.get_mut(&msg.author.id) {
-            state
-        } else {
-            just_log_error!(
-                "sending reply",
-                msg.reply(
-                    &ctx.http,
-                    "_This is your first message of the session. Did Gnome Chompski just wake up?_",
-                )
-                .await
-            );
-            all_bots.entry(msg.author.id).or_default()
-        };
+        // Usage is kept in the store rather than the bot, so it's answered here
+        if Command::read(&msg.content) == Some(Command::Usage) {
+            let reply = self.usage_reply(&user_id).await;
+            just_log_error!("sending reply", send_reply(&ctx, &msg, &reply, None).await);
+            return;
+        }
+
+        // Make sure nobody is talking to ChatGPT more than they're allowed to. Quotas are only a
+        // guard against overuse, so if they can't be checked, the message goes through anyway.
+        match self.take_quota(&user_id).await {
+            Ok(None) => {}
+            Ok(Some(explanation)) => {
+                just_log_error!(
+                    "sending reply",
+                    msg.reply(&ctx.http, format!("_{explanation}_")).await
+                );
+                return;
+            }
+            Err(e) => error!("Could not check quota for user {user_id}: {e:?}"),
+        }
 
         // Start typing, indicating to the user that we're doing some work
         let typing = just_log_error!("starting typing", msg.channel_id.start_typing(&ctx.http));
 
-        // Get the relevant reply from bot. We timeout after 10 seconds, as ChatGPT is probably
-        // overloaded and just won't reply to us.
-        let message_and_reply = match tokio::time::timeout(
-            Duration::from_secs(20),
-            state.handle(&msg.content),
-        )
-        .await
-        {
-            Ok(maybe_msg) => match maybe_msg {
-                Ok(msg) => msg,
-                Err(e) => {
-                    error!("Error while interacting with bot: {e:?}");
-                    MessageReply::reply("I'm sorry, I don't understand :(
+        // Show the conversation's reply as it streams in
+        let (updates, updates_rx) = watch::channel(String::new());
+        let streamer = tokio::spawn(stream_reply(ctx.clone(), msg.clone(), updates_rx));
+        let on_delta = move |delta: &str| updates.send_modify(|text| text.push_str(delta));
 
-_Something went wrong whilst communicating with Gnome Chompski. Please restate your reply and try again._")
-                }
-            },
-            Err(_) => MessageReply::reply(
-                "...I'm sorry, I wasn't paying attention. What were we talking about?
+        // Corrections take into account which of our messages the user was replying to
+        let replying_to = msg
+            .referenced_message
+            .as_ref()
+            .filter(|referenced| referenced.author.id == ctx.cache.current_user_id())
+            .map(|referenced| referenced.content.clone());
 
-_ChatGPT request timed out. Please write your reply again._
-",
-            ),
-        };
+        // Get the relevant reply from the session's bot. Once it's done, `on_delta` is dropped
+        // along with the rest of the handling, so no more updates are coming and the streamer
+        // can wrap up.
+        let content = msg.content.clone();
+        let message_and_reply = self
+            .with_bot(&ctx, msg.channel_id, &session_key, &user_id, |bot| {
+                Box::pin(async move {
+                    bot.handle_streaming(&content, replying_to.as_deref(), &on_delta)
+                        .await
+                })
+            })
+            .await;
+        let mut streamed = streamer.await.ok().flatten();
 
         // Stop typing before sending the message back
         let _ = typing.stop();
 
+        // Conversation replies get buttons for the common follow-ups
+        let (channel_buttons, reply_buttons) = if message_and_reply.follow_ups {
+            (Some(Buttons::Conversation), Some(Buttons::Correction))
+        } else {
+            (None, None)
+        };
+
         // Send the reply as a reply (wow!)
         if let Some(reply) = message_and_reply.reply {
-            just_log_error!("sending reply", msg.reply(&ctx.http, reply).await);
+            just_log_error!(
+                "sending reply",
+                send_reply(&ctx, &msg, &reply, reply_buttons).await
+            );
+        }
+
+        // Send the channel message, or finish off the one we've been streaming
+        match (message_and_reply.channel, streamed.as_mut()) {
+            (Some(message), Some(posted)) => just_log_error!(
+                "sending reply",
+                finish_streamed_reply(&ctx, posted, &message, channel_buttons).await
+            ),
+            (Some(message), None) => just_log_error!(
+                "sending reply",
+                send_reply(&ctx, &msg, &message, channel_buttons).await
+            ),
+            // Something went wrong part-way through, so tidy up the partial reply
+            (None, Some(posted)) => {
+                just_log_error!("deleting partial reply", posted.delete(&ctx.http).await)
+            }
+            (None, None) => {}
         }
+    }
 
-        // Send the channel message
-        if let Some(message) = message_and_reply.channel {
-            just_log_error!("sending reply", msg.reply(&ctx.http, message).await);
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::ApplicationCommand(interaction) => just_log_error!(
+                "handling slash command",
+                self.slash_command(&ctx, &interaction).await
+            ),
+            Interaction::MessageComponent(interaction) => just_log_error!(
+                "handling button click",
+                self.button_click(&ctx, &interaction).await
+            ),
+            _ => {}
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         warn!("{} is connected!", ready.user.name);
+
+        just_log_error!(
+            "registering slash commands",
+            SlashCommandList::set_global_application_commands(&ctx.http, slash::register).await
+        );
     }
 }
 
-pub async fn do_chat_bot(auth_strategy: AuthenticationStrategy) -> Result<()> {
-    let framework = StandardFramework::new().configure(|c| c.prefix("~"));
-
+pub async fn do_chat_bot(
+    auth_strategy: AuthenticationStrategy,
+    backend: Arc<dyn ChatBackend>,
+    store: Store,
+    settings: Settings,
+) -> Result<()> {
     let token =
         env::var(DISCORD_API_TOKEN).expect("Expected a DISCORD_API_TOKEN in the environment");
 
-    let intents = GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    let mut intents = GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    if settings.threads.is_some() {
+        intents |= GatewayIntents::GUILD_MESSAGES;
+    }
 
     let mut client = Client::builder(&token, intents)
-        .framework(framework)
-        .event_handler(Handler::new(auth_strategy))
+        .event_handler(Handler::new(auth_strategy, backend, store, settings))
         .await?;
 
     if let Err(why) = client.start().await {
@@ -217,6 +1151,52 @@ pub async fn do_chat_bot(auth_strategy: AuthenticationStrategy) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gpt::EchoBackend;
+
+    #[test]
+    fn test_error_reply_explains_backend_errors() {
+        let reply = error_reply(&BackendError::RateLimited { retry_after: None }.into());
+        assert!(reply.reply.unwrap().contains("talking too much"));
+
+        let reply = error_reply(&eyre::eyre!("something else"));
+        assert!(reply.reply.unwrap().contains("Something went wrong"));
+    }
+
+    #[tokio::test]
+    async fn test_bot_slots_are_locked_per_user() {
+        let handler = Handler::new(
+            AuthenticationStrategy::NoAuthentication,
+            Arc::new(EchoBackend),
+            Store::in_memory().await.unwrap(),
+            Settings::default(),
+        );
+
+        let slot = handler.bot_slot("1").await;
+        let _busy = slot.lock().await;
+
+        // The same user has to wait their turn..
+        assert!(handler.bot_slot("1").await.try_lock().is_none());
+        // ..but other users don't
+        assert!(handler.bot_slot("2").await.try_lock().is_some());
+        assert!(handler
+            .bot_slot(&thread_session(ChannelId(1)))
+            .await
+            .try_lock()
+            .is_some());
+    }
+
+    #[test]
+    fn test_buttons_go_on_the_last_part() {
+        let long_reply = "a".repeat(DISCORD_MESSAGE_LIMIT) + "\n\nb";
+        let parts: Vec<_> = parts_with_buttons(&long_reply, Some(Buttons::Conversation)).collect();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].1, None);
+        assert_eq!(parts[1], ("b".to_string(), Some(Buttons::Conversation)));
+
+        let parts: Vec<_> = parts_with_buttons("short", None).collect();
+        assert_eq!(parts, vec![("short".to_string(), None)]);
+    }
 
     #[test]
     fn test_just_log_error_passes_ok() {
@@ -1,36 +1,161 @@
-use eyre::{Context, Result};
+use async_trait::async_trait;
+use eyre::Result;
+use log::warn;
+use rand::Rng;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::env;
+use std::{env, fmt::Debug, future::Future, ops::AddAssign, sync::Arc, time::Duration};
+use thiserror::Error;
 
 const OPENAI_API_TOKEN: &str = "OPENAI_API_TOKEN";
-const COMPLETIONS_URL: &str = "https://api.openai.com/v1/chat/completions";
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 20;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+const SUMMARY_PROMPT: &str = "Summarise the following conversation in a few sentences, in English. Keep anything that would be needed to carry on the conversation naturally, such as the topics discussed and what the user has said about themselves. Only reply with the summary.";
+/// Once the history grows past this many messages (besides the system prompt), older ones are
+/// folded into a summary
+const SUMMARISE_AFTER_MESSAGES: usize = 24;
+/// Summarise early if the history uses more than this fraction of the available tokens
+const SUMMARISE_AT_TOKEN_FRACTION: f64 = 0.75;
+/// The most recent messages are never summarised, so the conversation flows naturally
+const KEEP_RECENT_MESSAGES: usize = 6;
+
+/// Rough number of characters per token in English text. Good enough for deciding when to trim
+/// the history.
+const CHARS_PER_TOKEN: usize = 4;
+/// Other languages (especially ones written in Cyrillic) are split into a lot more tokens, so
+/// anything that isn't plain ASCII is estimated conservatively
+const NON_ASCII_CHARS_PER_TOKEN: usize = 2;
+/// Tokens used by each message's role and formatting, on top of its content
+const TOKENS_PER_MESSAGE: usize = 4;
+/// Tokens left free for the reply when `max_tokens` isn't set
+const DEFAULT_REPLY_RESERVE: usize = 512;
+
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::Type)]
 #[serde(rename_all(serialize = "lowercase", deserialize = "lowercase"))]
-enum Role {
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
     System,
     User,
     Assistant,
 }
 
-#[derive(Debug, Serialize, Clone)]
-enum Model {
-    #[serde(rename(serialize = "gpt-3.5-turbo"))]
-    Gpt35Turbo,
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct MessageContent {
+    pub role: Role,
+    pub content: String,
+}
+
+/// Tokens used by a request, as reported by the backend.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct MessageContent {
-    role: Role,
-    content: String,
+impl Usage {
+    /// Estimates the usage of a request, for backends that don't report it
+    pub fn estimate(history: &[MessageContent], reply: &MessageContent) -> Self {
+        let prompt_tokens: usize = history.iter().map(MessageContent::estimate_tokens).sum();
+
+        Self {
+            prompt_tokens: prompt_tokens as u32,
+            completion_tokens: reply.estimate_tokens() as u32,
+        }
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// Overrides for a single request, on top of the backend's own settings
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestOptions {
+    pub temperature: Option<f32>,
+}
+
+/// A reply from the backend, along with how many tokens it took to produce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub message: MessageContent,
+    pub usage: Usage,
+}
+
+impl MessageContent {
+    /// Estimates how many tokens this message will use up in the model's context window
+    pub fn estimate_tokens(&self) -> usize {
+        let chars = self.content.chars().count();
+        let chars_per_token = if self.content.is_ascii() {
+            CHARS_PER_TOKEN
+        } else {
+            NON_ASCII_CHARS_PER_TOKEN
+        };
+
+        chars.div_ceil(chars_per_token) + TOKENS_PER_MESSAGE
+    }
+}
+
+/// Estimates the size of the context window for well-known models. Unknown models (e.g. local
+/// ones) are assumed to have a small window - configure it explicitly if yours is bigger.
+pub fn default_context_window(model: &str) -> usize {
+    if model.starts_with("gpt-4-32k") {
+        32_768
+    } else if model.starts_with("gpt-4") {
+        8_192
+    } else if model.starts_with("gpt-3.5-turbo-16k") {
+        16_384
+    } else {
+        4_096
+    }
+}
+
+/// Drops the oldest messages until the history fits in `max_tokens`. The system prompt and the
+/// latest message are always kept. Returns the number of messages dropped.
+fn truncate_history(history: &mut Vec<MessageContent>, max_tokens: usize) -> usize {
+    let mut total: usize = history.iter().map(MessageContent::estimate_tokens).sum();
+    let mut dropped = 0;
+
+    while total > max_tokens && history.len() > 2 {
+        total -= history.remove(1).estimate_tokens();
+        dropped += 1;
+    }
+
+    dropped
 }
 
 #[derive(Debug, Serialize)]
 struct ChatCompletion {
-    model: Model,
+    model: String,
     messages: Vec<MessageContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    /// Asks for a final event reporting the usage of the whole stream
+    include_usage: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -41,23 +166,572 @@ struct ChatChoice {
 #[derive(Debug, Deserialize, Clone)]
 struct ChatCompletionResponse {
     choices: Vec<ChatChoice>,
+    usage: Option<Usage>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct ChatDelta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ChatStreamChoice {
+    delta: ChatDelta,
+}
+
+/// A single server-sent event of a streamed completion
+#[derive(Debug, Deserialize, Clone)]
+struct ChatCompletionChunk {
+    choices: Vec<ChatStreamChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum StreamEvent {
+    Delta(String),
+    Usage(Usage),
+    Done,
+}
+
+/// Parses a line of a server-sent event stream. Some servers report usage alongside the content,
+/// so a line can hold both - or neither, if it has nothing new.
+fn parse_stream_line(line: &str) -> Result<Vec<StreamEvent>, BackendError> {
+    let Some(data) = line.strip_prefix("data:") else {
+        return Ok(vec![]);
+    };
+
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(vec![StreamEvent::Done]);
+    }
+
+    let chunk: ChatCompletionChunk = serde_json::from_str(data).map_err(|e| {
+        BackendError::BadResponse(format!("could not dese stream chunk ({e}): {data}"))
+    })?;
+
+    let delta = chunk
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.delta.content)
+        .filter(|content| !content.is_empty())
+        .map(StreamEvent::Delta);
+    let usage = chunk.usage.map(StreamEvent::Usage);
+
+    Ok(delta.into_iter().chain(usage).collect())
+}
+
+/// Collects bytes as they arrive, handing back complete lines. Works on bytes rather than text,
+/// as a chunk may end part-way through a multi-byte character.
 #[derive(Debug, Default)]
+struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(bytes);
+
+        let mut lines = vec![];
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            lines.push(
+                String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+            );
+        }
+
+        lines
+    }
+}
+
+/// Called with each new piece of a reply as it is streamed in
+pub type OnDelta = dyn Fn(&str) + Send + Sync;
+
+/// The ways in which talking to a backend can fail.
+#[derive(Debug, Error)]
+pub enum BackendError {
+    /// The backend didn't accept our credentials (or we didn't have any)
+    #[error("not authorised by the backend: {0}")]
+    Auth(String),
+    /// We're sending too many requests, or have run out of quota
+    #[error("rate limited by the backend")]
+    RateLimited { retry_after: Option<Duration> },
+    /// The backend is having a bad time
+    #[error("the backend is overloaded (status {status})")]
+    Overloaded {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    /// The backend replied, but not with anything we could use
+    #[error("bad response from the backend: {0}")]
+    BadResponse(String),
+    /// The backend didn't reply in time
+    #[error("timed out waiting for the backend")]
+    Timeout,
+    /// We couldn't reach the backend at all
+    #[error("could not reach the backend: {0}")]
+    Unreachable(String),
+}
+
+impl BackendError {
+    /// Whether it's worth trying the same request again
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BackendError::RateLimited { .. }
+                | BackendError::Overloaded { .. }
+                | BackendError::Timeout
+                | BackendError::Unreachable(_)
+        )
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            BackendError::RateLimited { retry_after }
+            | BackendError::Overloaded { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Works out what went wrong from an unsuccessful response
+    fn from_status(status: StatusCode, retry_after: Option<Duration>, body: &str) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => BackendError::Auth(format!(
+                "{status}. Check that {OPENAI_API_TOKEN} is set correctly"
+            )),
+            StatusCode::TOO_MANY_REQUESTS => BackendError::RateLimited { retry_after },
+            status if status.is_server_error() => BackendError::Overloaded {
+                status: status.as_u16(),
+                retry_after,
+            },
+            status => BackendError::BadResponse(format!("{status}: {body}")),
+        }
+    }
+}
+
+impl From<reqwest::Error> for BackendError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            BackendError::Timeout
+        } else if e.is_connect() {
+            BackendError::Unreachable(e.to_string())
+        } else {
+            BackendError::BadResponse(e.to_string())
+        }
+    }
+}
+
+/// Something that can continue a conversation, given its history so far.
+#[async_trait]
+pub trait ChatBackend: Debug + Send + Sync {
+    /// Returns the assistant's reply to the provided history.
+    async fn complete(&self, history: &[MessageContent]) -> Result<Completion, BackendError>;
+
+    /// Like `complete`, but calls `on_delta` with each piece of the reply as it arrives. Backends
+    /// that can't stream hand over the whole reply in one go, and may ignore the `options`.
+    async fn complete_streaming(
+        &self,
+        history: &[MessageContent],
+        _options: &RequestOptions,
+        on_delta: &OnDelta,
+    ) -> Result<Completion, BackendError> {
+        let completion = self.complete(history).await?;
+        on_delta(&completion.message.content);
+        Ok(completion)
+    }
+
+    /// The most tokens the history sent to `complete` may use, if there is a limit.
+    fn max_prompt_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    /// The longest a request can take to fail, including retries, if there is a limit. Callers
+    /// that give up on requests should wait at least this long, so the real error gets through.
+    fn max_request_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Settings for talking to OpenAI, or any server exposing an OpenAI-compatible API.
+#[derive(Debug, Clone)]
+pub struct OpenAiConfig {
+    /// Base URL of the API, e.g. `https://api.openai.com/v1`
+    pub base_url: String,
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// How many times to retry a request that failed for a temporary reason
+    pub max_retries: u32,
+    /// How long to wait for a single request before giving up on it. Streamed replies only have
+    /// to start within this time, and keep coming without a gap this long.
+    pub request_timeout: Duration,
+    /// Size of the model's context window, in tokens
+    pub context_window: usize,
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            context_window: default_context_window(DEFAULT_MODEL),
+        }
+    }
+}
+
+impl OpenAiConfig {
+    fn completions_url(&self) -> String {
+        format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+    }
+}
+
+/// How long to wait before the given retry attempt (starting at 0), or `None` if it's not worth
+/// waiting. Honours the server's `Retry-After` if it gave one, unless that would leave the user
+/// hanging for longer than we'd ever back off by ourselves - in which case it's better to give
+/// up and tell them to wait. Otherwise backs off exponentially. Either way, some jitter is added
+/// so that concurrent requests don't all retry at the same moment.
+fn backoff_delay(attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+    let backoff = match retry_after {
+        Some(retry_after) if retry_after > BACKOFF_MAX => return None,
+        Some(retry_after) => retry_after,
+        None => BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(BACKOFF_MAX),
+    };
+
+    let jitter = rand::thread_rng().gen_range(0..=BACKOFF_BASE.as_millis() as u64);
+    Some(backoff + Duration::from_millis(jitter))
+}
+
+/// Reads a `Retry-After` header, if it's given in seconds
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Talks to the OpenAI chat completions API.
+#[derive(Debug, Default)]
+pub struct OpenAiBackend {
+    client: reqwest::Client,
+    config: OpenAiConfig,
+}
+
+impl OpenAiBackend {
+    pub fn new(config: OpenAiConfig) -> Self {
+        // Streamed replies can take a lot longer than any single wait, so the timeout is applied
+        // per request (and per chunk, when streaming) rather than to the whole client
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.request_timeout)
+            .build()
+            .unwrap_or_default();
+
+        Self { client, config }
+    }
+
+    /// Sends a completion request, checking that it was successful. Non-streaming requests have to
+    /// finish within the request timeout, while streaming ones only have to start.
+    async fn send(
+        &self,
+        history: &[MessageContent],
+        options: &RequestOptions,
+        stream: bool,
+    ) -> Result<reqwest::Response, BackendError> {
+        // Self-hosted servers often don't need a token, but OpenAI always does
+        let token = env::var(OPENAI_API_TOKEN).ok();
+        if token.is_none() && self.config.base_url == DEFAULT_BASE_URL {
+            return Err(BackendError::Auth(format!(
+                "no {OPENAI_API_TOKEN} found in env"
+            )));
+        }
+
+        let body = ChatCompletion {
+            model: self.config.model.clone(),
+            messages: history.to_vec(),
+            temperature: options.temperature.or(self.config.temperature),
+            top_p: self.config.top_p,
+            max_tokens: self.config.max_tokens,
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+
+        let mut req = self
+            .client
+            .post(self.config.completions_url())
+            .header("Content-Type", "application/json")
+            .json(&body);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        if !stream {
+            req = req.timeout(self.config.request_timeout);
+        }
+
+        let res = tokio::time::timeout(self.config.request_timeout, req.send())
+            .await
+            .map_err(|_| BackendError::Timeout)??;
+
+        let status = res.status();
+        if !status.is_success() {
+            let retry_after = parse_retry_after(res.headers());
+            let body = res.text().await.unwrap_or_default();
+            return Err(BackendError::from_status(status, retry_after, &body));
+        }
+
+        Ok(res)
+    }
+
+    async fn request(&self, history: &[MessageContent]) -> Result<Completion, BackendError> {
+        let res = self
+            .send(history, &RequestOptions::default(), false)
+            .await?;
+
+        let res_value: Value = res.json().await?;
+        let response: ChatCompletionResponse =
+            serde_json::from_value(res_value.clone()).map_err(|e| {
+                BackendError::BadResponse(format!("could not dese json body ({e}): {res_value:#?}"))
+            })?;
+
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or_else(|| BackendError::BadResponse("no message returned".to_string()))?;
+        let usage = response
+            .usage
+            .unwrap_or_else(|| Usage::estimate(history, &message));
+
+        Ok(Completion { message, usage })
+    }
+
+    async fn stream_request(
+        &self,
+        history: &[MessageContent],
+        options: &RequestOptions,
+        on_delta: &OnDelta,
+    ) -> Result<Completion, BackendError> {
+        let mut res = self.send(history, options, true).await?;
+
+        let mut lines = LineBuffer::default();
+        let mut content = String::new();
+        let mut usage = None;
+
+        // Failures part-way through can't be retried, as the user has already seen some of
+        // the reply. Slow replies are fine, as long as the server doesn't go quiet for too long.
+        'stream: loop {
+            let next_chunk = tokio::time::timeout(self.config.request_timeout, res.chunk());
+            let chunk = match next_chunk.await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    return Err(BackendError::BadResponse(format!(
+                        "stream interrupted: {e}"
+                    )))
+                }
+                Err(_) if content.is_empty() => return Err(BackendError::Timeout),
+                Err(_) => {
+                    return Err(BackendError::BadResponse(
+                        "stream stalled part-way through".to_string(),
+                    ))
+                }
+            };
+
+            for line in lines.push(&chunk) {
+                for event in parse_stream_line(&line)? {
+                    match event {
+                        StreamEvent::Delta(delta) => {
+                            on_delta(&delta);
+                            content.push_str(&delta);
+                        }
+                        StreamEvent::Usage(reported) => usage = Some(reported),
+                        StreamEvent::Done => break 'stream,
+                    }
+                }
+            }
+        }
+
+        if content.is_empty() {
+            return Err(BackendError::BadResponse("no message returned".to_string()));
+        }
+
+        let message = MessageContent {
+            role: Role::Assistant,
+            content,
+        };
+        let usage = usage.unwrap_or_else(|| Usage::estimate(history, &message));
+
+        Ok(Completion { message, usage })
+    }
+
+    /// Makes a request, retrying it if it failed for a temporary reason
+    async fn with_retries<F, Fut>(&self, mut request: F) -> Result<Completion, BackendError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Completion, BackendError>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match request().await {
+                Err(e) if e.is_retryable() && attempt < self.config.max_retries => {
+                    let Some(delay) = backoff_delay(attempt, e.retry_after()) else {
+                        warn!("Request failed ({e}), and the server wants us to wait too long");
+                        return Err(e);
+                    };
+                    warn!(
+                        "Request failed ({e}), retrying in {delay:?} (attempt {}/{})",
+                        attempt + 1,
+                        self.config.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ChatBackend for OpenAiBackend {
+    async fn complete(&self, history: &[MessageContent]) -> Result<Completion, BackendError> {
+        self.with_retries(|| self.request(history)).await
+    }
+
+    async fn complete_streaming(
+        &self,
+        history: &[MessageContent],
+        options: &RequestOptions,
+        on_delta: &OnDelta,
+    ) -> Result<Completion, BackendError> {
+        self.with_retries(|| self.stream_request(history, options, on_delta))
+            .await
+    }
+
+    fn max_prompt_tokens(&self) -> Option<usize> {
+        let reserve = self
+            .config
+            .max_tokens
+            .map(|max_tokens| max_tokens as usize)
+            .unwrap_or(DEFAULT_REPLY_RESERVE);
+
+        Some(self.config.context_window.saturating_sub(reserve))
+    }
+
+    fn max_request_duration(&self) -> Option<Duration> {
+        let retries = self.config.max_retries;
+        let attempts = self.config.request_timeout.saturating_mul(retries + 1);
+        let backoff = (BACKOFF_MAX + BACKOFF_BASE).saturating_mul(retries);
+
+        Some(attempts + backoff)
+    }
+}
+
+/// Replies with whatever the user last said. Useful for trying things out without an LLM.
+#[derive(Debug, Default)]
+pub struct EchoBackend;
+
+#[async_trait]
+impl ChatBackend for EchoBackend {
+    async fn complete(&self, history: &[MessageContent]) -> Result<Completion, BackendError> {
+        let last_user_message = history
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map(|message| message.content.clone())
+            .unwrap_or_default();
+
+        let message = MessageContent {
+            role: Role::Assistant,
+            content: last_user_message,
+        };
+        let usage = Usage::estimate(history, &message);
+
+        Ok(Completion { message, usage })
+    }
+}
+
+#[derive(Debug)]
 pub struct Conversation {
+    backend: Arc<dyn ChatBackend>,
     history: Vec<MessageContent>,
+    /// Summary of older messages that have been dropped from the history
+    summary: Option<String>,
+    /// Tokens used since the usage was last taken
+    usage: Usage,
 }
 
 impl Conversation {
-    pub fn new(prompt: impl Into<String>) -> Self {
+    pub fn new(backend: Arc<dyn ChatBackend>, prompt: impl Into<String>) -> Self {
         Self {
+            backend,
             history: vec![MessageContent {
                 role: Role::System,
                 content: prompt.into(),
             }],
+            summary: None,
+            usage: Usage::default(),
+        }
+    }
+
+    /// Picks up a conversation from a previously saved history and summary. The history should
+    /// begin with the system prompt - if it is empty, `prompt` is used instead.
+    pub fn from_history(
+        backend: Arc<dyn ChatBackend>,
+        prompt: impl Into<String>,
+        history: Vec<MessageContent>,
+        summary: Option<String>,
+    ) -> Self {
+        if history.is_empty() {
+            Self::new(backend, prompt)
+        } else {
+            Self {
+                backend,
+                history,
+                summary,
+                usage: Usage::default(),
+            }
+        }
+    }
+
+    /// Summary of the older parts of the conversation, if there have been enough to summarise
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Replaces the system prompt, keeping the rest of the conversation
+    pub fn set_prompt(&mut self, prompt: impl Into<String>) {
+        if let Some(system) = self.history.first_mut() {
+            system.content = prompt.into();
         }
     }
 
+    /// Returns the tokens used since this was last called
+    pub fn take_usage(&mut self) -> Usage {
+        std::mem::take(&mut self.usage)
+    }
+
+    pub fn history(&self) -> &[MessageContent] {
+        &self.history
+    }
+
     pub fn forget_last(&mut self) -> Option<String> {
         if self.history.len() > 1 {
             Some(self.history.remove(self.history.len() - 1).content)
@@ -66,56 +740,535 @@ impl Conversation {
         }
     }
 
-    pub async fn ask(prompt: impl Into<String>, message: impl Into<String>) -> Result<String> {
-        Conversation::new(prompt).message(message).await
+    /// The assistant's latest reply, if it was the last thing said
+    pub fn last_reply(&self) -> Option<&str> {
+        self.history
+            .last()
+            .filter(|message| message.role == Role::Assistant)
+            .map(|message| message.content.as_str())
+    }
+
+    /// The user's latest message, if there's one in the history
+    pub fn last_message(&self) -> Option<&str> {
+        self.history
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map(|message| message.content.as_str())
+    }
+
+    /// The assistant's reply that the user's latest message was answering, if there was one
+    pub fn last_message_context(&self) -> Option<&str> {
+        let before_last_message = self
+            .history
+            .iter()
+            .rposition(|message| message.role == Role::User)?;
+
+        self.history[..before_last_message]
+            .last()
+            .filter(|message| message.role == Role::Assistant)
+            .map(|message| message.content.as_str())
+    }
+
+    /// Asks for a different reply to the user's latest message, replacing the current one. Returns
+    /// `None` if there's no reply to replace.
+    pub async fn regenerate(
+        &mut self,
+        options: &RequestOptions,
+        on_delta: &OnDelta,
+    ) -> Result<Option<String>> {
+        if self.last_reply().is_none() {
+            return Ok(None);
+        }
+
+        let previous = self.history.pop().expect("checked there was a reply");
+        match self.fetch_response(options, on_delta).await {
+            Ok(response) => Ok(Some(response.content)),
+            Err(e) => {
+                // Keep the old reply rather than leaving the user's message unanswered
+                self.history.push(previous);
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn ask(
+        backend: Arc<dyn ChatBackend>,
+        prompt: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Result<(String, Usage)> {
+        let mut conversation = Conversation::new(backend, prompt);
+        let reply = conversation.message(message).await?;
+        Ok((reply, conversation.take_usage()))
     }
 
     pub async fn message(&mut self, content: impl Into<String>) -> Result<String> {
+        self.message_streaming(content, &|_| {}).await
+    }
+
+    /// Like `message`, but calls `on_delta` with each piece of the reply as it arrives
+    pub async fn message_streaming(
+        &mut self,
+        content: impl Into<String>,
+        on_delta: &OnDelta,
+    ) -> Result<String> {
         self.history.push(MessageContent {
             role: Role::User,
             content: content.into(),
         });
 
-        let response = self.fetch_response().await?;
+        let response = self
+            .fetch_response(&RequestOptions::default(), on_delta)
+            .await?;
         Ok(response.content)
     }
 
-    async fn fetch_response(&mut self) -> Result<MessageContent> {
-        let token = env::var(OPENAI_API_TOKEN)
-            .unwrap_or_else(|_| panic!("no {OPENAI_API_TOKEN} found in env"));
+    fn summary_message(&self) -> Option<MessageContent> {
+        self.summary.as_ref().map(|summary| MessageContent {
+            role: Role::System,
+            content: format!("Summary of the conversation so far: {summary}"),
+        })
+    }
 
-        let body = ChatCompletion {
-            model: Model::Gpt35Turbo,
-            messages: self.history.clone(),
-            temperature: None,
-        };
-        let ser_body = serde_json::to_string(&body)?;
+    /// The messages to send to the backend: the history, with the summary after the prompt
+    fn request_messages(&self) -> Vec<MessageContent> {
+        let mut messages = self.history.clone();
+        if let Some(summary) = self.summary_message() {
+            messages.insert(1.min(messages.len()), summary);
+        }
+        messages
+    }
 
-        let client = reqwest::Client::new();
-        let res = client
-            .post(COMPLETIONS_URL)
-            .header("Authorization", format!("Bearer {token}"))
-            .header("Content-Type", "application/json")
-            .body(ser_body)
-            .send()
-            .await?;
+    fn should_summarise(&self) -> bool {
+        let messages = self.history.len().saturating_sub(1);
+        if messages <= KEEP_RECENT_MESSAGES {
+            return false;
+        }
 
-        let res_value: Value = res
-            .json()
-            .await
-            .wrap_err("could not dese response body at all")?;
+        let tokens: usize = self
+            .request_messages()
+            .iter()
+            .map(MessageContent::estimate_tokens)
+            .sum();
+        let over_token_threshold = self
+            .backend
+            .max_prompt_tokens()
+            .is_some_and(|max| tokens as f64 > max as f64 * SUMMARISE_AT_TOKEN_FRACTION);
 
-        let response: ChatCompletionResponse = serde_json::from_value(res_value.clone())
-            .wrap_err_with(|| format!("could not dese json body: {res_value:#?}"))?;
-        let message = response
-            .choices
-            .first()
-            .expect("no message returned")
-            .message
-            .clone();
+        messages > SUMMARISE_AFTER_MESSAGES || over_token_threshold
+    }
+
+    /// Asks the backend to fold all but the most recent messages into the summary
+    async fn summarise(&mut self) -> Result<(), BackendError> {
+        let cutoff = self.history.len() - KEEP_RECENT_MESSAGES;
+
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript.push_str(&format!("Earlier summary: {summary}\n\n"));
+        }
+        for message in &self.history[1..cutoff] {
+            let speaker = match message.role {
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                Role::System => continue,
+            };
+            transcript.push_str(&format!("{speaker}: {}\n", message.content));
+        }
+
+        let request = [
+            MessageContent {
+                role: Role::System,
+                content: SUMMARY_PROMPT.to_string(),
+            },
+            MessageContent {
+                role: Role::User,
+                content: transcript,
+            },
+        ];
+        let summary = self.backend.complete(&request).await?;
+
+        self.usage += summary.usage;
+        self.summary = Some(summary.message.content);
+        self.history.drain(1..cutoff);
+
+        Ok(())
+    }
+
+    async fn fetch_response(
+        &mut self,
+        options: &RequestOptions,
+        on_delta: &OnDelta,
+    ) -> Result<MessageContent> {
+        if self.should_summarise() {
+            if let Err(e) = self.summarise().await {
+                warn!("Could not summarise the conversation, falling back to dropping old messages: {e}");
+            }
+        }
+
+        if let Some(max_tokens) = self.backend.max_prompt_tokens() {
+            let summary_tokens = self
+                .summary_message()
+                .map_or(0, |summary| summary.estimate_tokens());
+            let dropped =
+                truncate_history(&mut self.history, max_tokens.saturating_sub(summary_tokens));
+            if dropped > 0 {
+                warn!("Dropped {dropped} old messages to fit the context window");
+            }
+        }
+
+        let Completion { message, usage } = self
+            .backend
+            .complete_streaming(&self.request_messages(), options, on_delta)
+            .await?;
+        self.usage += usage;
 
         self.history.push(message.clone());
 
         Ok(message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completions_url() {
+        let mut config = OpenAiConfig::default();
+        assert_eq!(
+            config.completions_url(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+
+        config.base_url = "http://localhost:8080/v1/".to_string();
+        assert_eq!(
+            config.completions_url(),
+            "http://localhost:8080/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn test_unset_sampling_parameters_are_omitted() {
+        let body = ChatCompletion {
+            model: DEFAULT_MODEL.to_string(),
+            messages: vec![],
+            temperature: Some(0.5),
+            top_p: None,
+            max_tokens: None,
+            stream: false,
+            stream_options: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            r#"{"model":"gpt-3.5-turbo","messages":[],"temperature":0.5}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_stream_lines() {
+        assert_eq!(
+            parse_stream_line(r#"data: {"choices":[{"delta":{"content":"Cze"}}]}"#).unwrap(),
+            vec![StreamEvent::Delta("Cze".to_string())]
+        );
+        assert_eq!(
+            parse_stream_line(r#"data: {"choices":[{"delta":{"role":"assistant"}}]}"#).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            parse_stream_line("data: [DONE]").unwrap(),
+            vec![StreamEvent::Done]
+        );
+        assert_eq!(
+            parse_stream_line(
+                r#"data: {"choices":[],"usage":{"prompt_tokens":9,"completion_tokens":3,"total_tokens":12}}"#
+            )
+            .unwrap(),
+            vec![StreamEvent::Usage(Usage {
+                prompt_tokens: 9,
+                completion_tokens: 3
+            })]
+        );
+        assert_eq!(
+            parse_stream_line(
+                r#"data: {"choices":[{"delta":{"content":"ść"}}],"usage":{"prompt_tokens":9,"completion_tokens":4}}"#
+            )
+            .unwrap(),
+            vec![
+                StreamEvent::Delta("ść".to_string()),
+                StreamEvent::Usage(Usage {
+                    prompt_tokens: 9,
+                    completion_tokens: 4
+                })
+            ]
+        );
+        assert!(parse_stream_line("").unwrap().is_empty());
+        assert!(parse_stream_line(": keep-alive").unwrap().is_empty());
+        assert!(parse_stream_line("data: {oops").is_err());
+    }
+
+    #[test]
+    fn test_line_buffer_handles_split_chunks() {
+        let mut lines = LineBuffer::default();
+        let text = "data: cześć\r\n\ndata: [DONE]\n".as_bytes();
+
+        // Split part-way through the 'ś'
+        let split = "data: cze".len() + 1;
+        assert!(lines.push(&text[..split]).is_empty());
+        assert_eq!(
+            lines.push(&text[split..]),
+            vec!["data: cześć", "", "data: [DONE]"]
+        );
+    }
+
+    #[test]
+    fn test_classifies_failed_responses() {
+        assert!(matches!(
+            BackendError::from_status(StatusCode::UNAUTHORIZED, None, ""),
+            BackendError::Auth(_)
+        ));
+        assert!(matches!(
+            BackendError::from_status(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(Duration::from_secs(3)),
+                ""
+            ),
+            BackendError::RateLimited {
+                retry_after: Some(d)
+            } if d == Duration::from_secs(3)
+        ));
+        assert!(matches!(
+            BackendError::from_status(StatusCode::SERVICE_UNAVAILABLE, None, ""),
+            BackendError::Overloaded { status: 503, .. }
+        ));
+        assert!(matches!(
+            BackendError::from_status(StatusCode::BAD_REQUEST, None, "oops"),
+            BackendError::BadResponse(body) if body.contains("oops")
+        ));
+
+        assert!(BackendError::Timeout.is_retryable());
+        assert!(!BackendError::Auth(String::new()).is_retryable());
+        assert!(!BackendError::BadResponse(String::new()).is_retryable());
+    }
+
+    #[test]
+    fn test_parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+
+        // HTTP dates aren't supported, so we fall back to our own backoff
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_backoff_grows_and_honours_retry_after() {
+        let jitter = BACKOFF_BASE;
+
+        for attempt in 0..3 {
+            let delay = backoff_delay(attempt, None).unwrap();
+            let expected = BACKOFF_BASE * 2u32.pow(attempt);
+            assert!(delay >= expected && delay <= expected + jitter);
+        }
+
+        assert!(backoff_delay(20, None).unwrap() <= BACKOFF_MAX + jitter);
+
+        let delay = backoff_delay(0, Some(Duration::from_secs(5))).unwrap();
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(5) + jitter);
+
+        // Rather than hold the user up for ages, we give up and tell them to wait
+        assert_eq!(backoff_delay(0, Some(Duration::from_secs(600))), None);
+    }
+
+    #[test]
+    fn test_max_request_duration_covers_every_retry() {
+        let backend = OpenAiBackend::new(OpenAiConfig {
+            max_retries: 2,
+            request_timeout: Duration::from_secs(10),
+            ..OpenAiConfig::default()
+        });
+
+        assert_eq!(
+            backend.max_request_duration(),
+            Some(Duration::from_secs(30) + (BACKOFF_MAX + BACKOFF_BASE) * 2)
+        );
+        assert_eq!(EchoBackend.max_request_duration(), None);
+    }
+
+    fn message(role: Role, content: &str) -> MessageContent {
+        MessageContent {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(
+            message(Role::User, "").estimate_tokens(),
+            TOKENS_PER_MESSAGE
+        );
+        assert_eq!(
+            message(Role::User, "hello").estimate_tokens(),
+            2 + TOKENS_PER_MESSAGE
+        );
+        assert_eq!(
+            message(Role::User, "żółć").estimate_tokens(),
+            2 + TOKENS_PER_MESSAGE
+        );
+        assert_eq!(
+            message(Role::User, "cześć").estimate_tokens(),
+            3 + TOKENS_PER_MESSAGE
+        );
+        assert_eq!(
+            message(Role::User, "Привіт, як справи?").estimate_tokens(),
+            9 + TOKENS_PER_MESSAGE
+        );
+    }
+
+    #[test]
+    fn test_default_context_windows() {
+        assert_eq!(default_context_window("gpt-3.5-turbo"), 4_096);
+        assert_eq!(default_context_window("gpt-3.5-turbo-16k"), 16_384);
+        assert_eq!(default_context_window("gpt-4-0613"), 8_192);
+        assert_eq!(default_context_window("gpt-4-32k"), 32_768);
+        assert_eq!(default_context_window("llama-2-7b"), 4_096);
+    }
+
+    #[test]
+    fn test_truncate_history_keeps_prompt_and_latest_message() {
+        // Each of these is 5 tokens
+        let mut history = vec![
+            message(Role::System, "abcd"),
+            message(Role::User, "1"),
+            message(Role::Assistant, "2"),
+            message(Role::User, "3"),
+            message(Role::Assistant, "4"),
+            message(Role::User, "5"),
+        ];
+
+        assert_eq!(truncate_history(&mut history, 30), 0);
+        assert_eq!(history.len(), 6);
+
+        assert_eq!(truncate_history(&mut history, 15), 3);
+        assert_eq!(
+            history,
+            vec![
+                message(Role::System, "abcd"),
+                message(Role::Assistant, "4"),
+                message(Role::User, "5"),
+            ]
+        );
+
+        assert_eq!(truncate_history(&mut history, 0), 1);
+        assert_eq!(
+            history,
+            vec![message(Role::System, "abcd"), message(Role::User, "5")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conversation_records_history() {
+        let mut conversation = Conversation::new(Arc::new(EchoBackend), "prompt");
+
+        assert_eq!(conversation.message("cześć").await.unwrap(), "cześć");
+        assert_eq!(conversation.history.len(), 3);
+
+        assert_eq!(conversation.forget_last(), Some("cześć".to_string()));
+        assert_eq!(conversation.forget_last(), Some("cześć".to_string()));
+        assert_eq!(conversation.forget_last(), None);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_regenerates_last_reply() {
+        let mut conversation = Conversation::new(Arc::new(EchoBackend), "prompt");
+        assert_eq!(
+            conversation
+                .regenerate(&RequestOptions::default(), &|_| {})
+                .await
+                .unwrap(),
+            None
+        );
+
+        conversation.message("cześć").await.unwrap();
+        assert_eq!(conversation.last_reply(), Some("cześć"));
+        assert_eq!(conversation.last_message(), Some("cześć"));
+        assert_eq!(conversation.last_message_context(), None);
+
+        conversation.message("jak się masz?").await.unwrap();
+        assert_eq!(conversation.last_message_context(), Some("cześć"));
+
+        assert_eq!(
+            conversation
+                .regenerate(&RequestOptions::default(), &|_| {})
+                .await
+                .unwrap(),
+            Some("jak się masz?".to_string())
+        );
+        assert_eq!(conversation.history.len(), 5);
+
+        conversation.forget_last();
+        assert_eq!(conversation.last_reply(), None);
+        assert_eq!(
+            conversation
+                .regenerate(&RequestOptions::default(), &|_| {})
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conversation_tracks_usage() {
+        let mut conversation = Conversation::new(Arc::new(EchoBackend), "abcd");
+
+        conversation.message("1").await.unwrap();
+        conversation.message("2").await.unwrap();
+
+        // Each message is 5 tokens. The first request sends 2 messages, the second sends 4.
+        assert_eq!(
+            conversation.take_usage(),
+            Usage {
+                prompt_tokens: 30,
+                completion_tokens: 10,
+            }
+        );
+        assert_eq!(conversation.take_usage(), Usage::default());
+    }
+
+    #[tokio::test]
+    async fn test_conversation_summarises_old_messages() {
+        let mut conversation = Conversation::new(Arc::new(EchoBackend), "prompt");
+
+        for i in 0..SUMMARISE_AFTER_MESSAGES / 2 {
+            conversation.message(format!("message {i}")).await.unwrap();
+        }
+        assert_eq!(conversation.summary(), None);
+
+        conversation.message("one more").await.unwrap();
+
+        // The echo backend "summarises" by repeating the transcript back
+        let summary = conversation.summary().unwrap();
+        assert!(summary.contains("user: message 0"));
+        assert!(!summary.contains("one more"));
+        // The prompt, the recent messages, and the reply to the latest one
+        assert_eq!(conversation.history.len(), KEEP_RECENT_MESSAGES + 2);
+        assert_eq!(
+            conversation.history.last().unwrap().content,
+            "one more".to_string()
+        );
+
+        // The summary is sent along with the rest of the conversation
+        let messages = conversation.request_messages();
+        assert_eq!(messages[0].content, "prompt");
+        assert!(messages[1]
+            .content
+            .starts_with("Summary of the conversation so far"));
+    }
+}
@@ -2,27 +2,48 @@ use std::{
     fs::File,
     io::{BufRead, BufReader},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use authentication::AuthenticationStrategy;
-use clap::{Parser, ValueHint};
-use discord::do_chat_bot;
+use authentication::{generate_token, Access, AccessList, AuthenticationStrategy, GuildRoles};
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use discord::{do_chat_bot, Settings, ThreadSettings};
 use dotenvy::dotenv;
 use eyre::{bail, Result};
+use gpt::{
+    default_context_window, ChatBackend, EchoBackend, OpenAiBackend, OpenAiConfig,
+    DEFAULT_BASE_URL, DEFAULT_MAX_RETRIES, DEFAULT_MODEL, DEFAULT_REQUEST_TIMEOUT_SECS,
+};
 use log::{warn, LevelFilter};
-use store::Store;
+use model::Level;
+use quota::Limits;
+use repl::do_repl;
+use serenity::model::prelude::{ChannelId, UserId};
+use store::{now_secs, Store, DAY_SECS};
+use usage::{Pricing, DEFAULT_COMPLETION_PRICE, DEFAULT_PROMPT_PRICE};
 
+mod admin;
 mod authentication;
+mod buttons;
+mod chunk;
 mod discord;
 mod gpt;
 mod model;
+mod quota;
+mod repl;
+mod slash;
 mod store;
+mod usage;
 
 const DEFAULT_DATA_DIR: &str = "var/data";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Location for application-related data. Defaults to var/data
     #[arg(long, value_hint = ValueHint::DirPath, value_parser)]
     data_dir: Option<PathBuf>,
@@ -30,6 +51,239 @@ pub struct Args {
     /// Location of a tokens file. If provided, enables the token-based auth strategy.
     #[arg(long, value_hint = ValueHint::DirPath, value_parser)]
     tokens_file: Option<PathBuf>,
+
+    /// ID of a Discord server. If provided, only members of this server (with one of
+    /// `--role-ids`, if given) can chat with Gnome Chompski
+    #[arg(long, env = "GUILD_ID", conflicts_with = "tokens_file")]
+    guild_id: Option<u64>,
+
+    /// Comma-separated IDs of the server roles that are allowed to chat
+    #[arg(long, env = "ROLE_IDS", value_delimiter = ',', requires = "guild_id")]
+    role_ids: Vec<u64>,
+
+    /// ID of a server channel to chat in. Learners who mention Gnome Chompski there get a thread
+    /// of their own to chat in. Only DMs are used if not provided
+    #[arg(long, env = "THREAD_CHANNEL_ID")]
+    thread_channel: Option<u64>,
+
+    /// Make learners' threads visible to everyone in the server, rather than just the learner
+    #[arg(long, env = "PUBLIC_THREADS", requires = "thread_channel")]
+    public_threads: bool,
+
+    /// Comma-separated Discord user IDs of the operators, who can use `!admin` commands
+    #[arg(long, env = "ADMIN_IDS", value_delimiter = ',')]
+    admin_ids: Vec<u64>,
+
+    /// Only chat with users who have been added with `access allow`. Banned users are always
+    /// turned away, whether or not this is set
+    #[arg(long, env = "ALLOWLIST_ONLY", conflicts_with_all = ["tokens_file", "guild_id"])]
+    allowlist_only: bool,
+
+    /// Seconds to remember whether someone has the right role, before asking Discord again.
+    /// Defaults to 300
+    #[arg(long, env = "ROLE_CACHE_SECS", default_value_t = 300)]
+    role_cache_secs: u64,
+
+    /// Which LLM backend to talk to. Defaults to openai
+    #[arg(long, value_enum, default_value_t = Backend::OpenAi)]
+    backend: Backend,
+
+    /// Base URL of an OpenAI-compatible API. Defaults to https://api.openai.com/v1
+    #[arg(long, env = "OPENAI_BASE_URL", default_value = DEFAULT_BASE_URL)]
+    openai_base_url: String,
+
+    /// Name of the model to request completions from. Defaults to gpt-3.5-turbo
+    #[arg(long, env = "OPENAI_MODEL", default_value = DEFAULT_MODEL)]
+    model: String,
+
+    /// Sampling temperature. Uses the server's default if not provided
+    #[arg(long, env = "OPENAI_TEMPERATURE")]
+    temperature: Option<f32>,
+
+    /// Sampling temperature for `!retry` and the Regenerate button, so that the new reply is
+    /// more likely to differ. Uses `--temperature` if not provided
+    #[arg(long, env = "OPENAI_RETRY_TEMPERATURE")]
+    retry_temperature: Option<f32>,
+
+    /// Nucleus sampling probability mass. Uses the server's default if not provided
+    #[arg(long, env = "OPENAI_TOP_P")]
+    top_p: Option<f32>,
+
+    /// Maximum number of tokens to generate per reply. Uses the server's default if not provided
+    #[arg(long, env = "OPENAI_MAX_TOKENS")]
+    max_tokens: Option<u32>,
+
+    /// How many times to retry a request that was rate-limited, timed out or hit an overloaded
+    /// server. Defaults to 3
+    #[arg(long, env = "OPENAI_MAX_RETRIES", default_value_t = DEFAULT_MAX_RETRIES)]
+    max_retries: u32,
+
+    /// Seconds to wait for a single request before giving up on it. Streamed replies can take
+    /// longer, as long as they never stall for this long. Defaults to 20
+    #[arg(long, env = "OPENAI_REQUEST_TIMEOUT", default_value_t = DEFAULT_REQUEST_TIMEOUT_SECS)]
+    request_timeout: u64,
+
+    /// Size of the model's context window, in tokens. Old messages are dropped from the
+    /// conversation to stay within it. Defaults to a best guess based on the model name
+    #[arg(long, env = "OPENAI_CONTEXT_WINDOW")]
+    context_window: Option<usize>,
+
+    /// CEFR level (A1-C2) for new learners. Learners can change their own level with `!level`.
+    /// Defaults to A2
+    #[arg(long, env = "DEFAULT_LEVEL", default_value_t = Level::default())]
+    default_level: Level,
+
+    /// Price per 1K prompt tokens in USD, used for usage reports. Defaults to 0.0015
+    #[arg(long, env = "PROMPT_PRICE", default_value_t = DEFAULT_PROMPT_PRICE)]
+    prompt_price: f64,
+
+    /// Price per 1K completion tokens in USD, used for usage reports. Defaults to 0.002
+    #[arg(long, env = "COMPLETION_PRICE", default_value_t = DEFAULT_COMPLETION_PRICE)]
+    completion_price: f64,
+
+    /// How many messages each user can send per minute. Unlimited if not provided
+    #[arg(long, env = "MESSAGES_PER_MINUTE")]
+    messages_per_minute: Option<u32>,
+
+    /// How many messages each user can send per day. Unlimited if not provided
+    #[arg(long, env = "REQUESTS_PER_DAY")]
+    requests_per_day: Option<u32>,
+
+    /// How many tokens each user can use per day. Unlimited if not provided
+    #[arg(long, env = "TOKENS_PER_DAY")]
+    tokens_per_day: Option<u32>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Run Gnome Chompski as a Discord bot (the default)
+    Discord,
+    /// Chat with Gnome Chompski from the terminal
+    Repl,
+    /// Manage the tokens users can authenticate with
+    Tokens {
+        #[command(subcommand)]
+        command: TokensCommand,
+    },
+    /// Manage which users are always allowed in, or kept out
+    Access {
+        #[command(subcommand)]
+        command: AccessCommand,
+    },
+    /// Report how many tokens have been used, per user and command
+    Usage {
+        /// Only report usage for this Discord user ID
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokensCommand {
+    /// List all tokens, and who they're allocated to
+    List,
+    /// Add the given tokens
+    Add {
+        #[arg(required = true)]
+        tokens: Vec<String>,
+        #[command(flatten)]
+        options: NewTokenOptions,
+    },
+    /// Generate and add some new random tokens
+    Generate {
+        /// How many tokens to generate
+        #[arg(default_value_t = 1)]
+        count: usize,
+        #[command(flatten)]
+        options: NewTokenOptions,
+    },
+    /// Revoke a token, so that it can no longer be used and anyone who used it has to
+    /// authenticate again
+    Revoke { token: String },
+    /// Free up a user's token, so that they have to authenticate again
+    Unassign { user: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AccessCommand {
+    /// List everyone who has been allowed or banned
+    List,
+    /// Always let a user in, whatever the auth strategy
+    Allow { user: String },
+    /// Never let a user in, even if they have a token
+    Ban { user: String },
+    /// Take a user off the allow or ban list
+    Remove { user: String },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct NewTokenOptions {
+    /// Stop the tokens from being redeemed after this many days. Never expire if not provided
+    #[arg(long)]
+    expires_in_days: Option<u32>,
+
+    /// How many users can redeem each token, e.g. for a whole class. Defaults to 1
+    #[arg(long, default_value_t = 1)]
+    max_uses: u32,
+}
+
+impl NewTokenOptions {
+    fn expires_at(&self) -> Option<i64> {
+        self.expires_in_days
+            .map(|days| now_secs() + i64::from(days) * DAY_SECS)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Backend {
+    /// The OpenAI chat completions API
+    #[value(name = "openai")]
+    OpenAi,
+    /// Echoes back whatever the user said. Useful for testing without an API account
+    Echo,
+}
+
+impl Args {
+    fn openai_config(&self) -> OpenAiConfig {
+        OpenAiConfig {
+            base_url: self.openai_base_url.clone(),
+            model: self.model.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            max_retries: self.max_retries,
+            request_timeout: Duration::from_secs(self.request_timeout),
+            context_window: self
+                .context_window
+                .unwrap_or_else(|| default_context_window(&self.model)),
+        }
+    }
+
+    fn pricing(&self) -> Pricing {
+        Pricing {
+            prompt: self.prompt_price,
+            completion: self.completion_price,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            messages_per_minute: self.messages_per_minute,
+            requests_per_day: self.requests_per_day,
+            tokens_per_day: self.tokens_per_day,
+        }
+    }
+
+    fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or(DEFAULT_DATA_DIR.into())
+    }
+
+    fn build_backend(&self) -> Arc<dyn ChatBackend> {
+        match self.backend {
+            Backend::OpenAi => Arc::new(OpenAiBackend::new(self.openai_config())),
+            Backend::Echo => Arc::new(EchoBackend),
+        }
+    }
 }
 
 #[tokio::main]
@@ -40,20 +294,157 @@ async fn main() -> Result<()> {
     dotenv().expect("could not instantiate dotenv");
 
     let args = Args::parse();
+    let backend = args.build_backend();
+
+    warn!(
+        "Starting with backend={:?} model={} base-url={}",
+        args.backend, args.model, args.openai_base_url
+    );
+
+    match &args.command {
+        Some(Commands::Repl) => do_repl(backend, args.default_level, args.retry_temperature).await,
+        Some(Commands::Tokens { command }) => run_tokens(&args, command).await,
+        Some(Commands::Access { command }) => run_access(&args, command).await,
+        Some(Commands::Usage { user }) => run_usage(&args, user.as_deref()).await,
+        Some(Commands::Discord) | None => run_discord(args, backend).await,
+    }
+}
+
+async fn run_tokens(args: &Args, command: &TokensCommand) -> Result<()> {
+    let store = Store::connect(&args.data_dir()).await?;
+
+    match command {
+        TokensCommand::List => {
+            let now = now_secs();
+            for entry in store.list_tokens().await? {
+                let users = if entry.user_ids.is_empty() {
+                    "-".to_string()
+                } else {
+                    entry.user_ids.join(",")
+                };
+                println!(
+                    "{}\t{}/{}\t{}\t{}",
+                    entry.token,
+                    entry.user_ids.len(),
+                    entry.max_uses,
+                    entry.status(now),
+                    users
+                );
+            }
+        }
+        TokensCommand::Add { tokens, options } => {
+            store
+                .add_tokens(tokens, options.expires_at(), options.max_uses)
+                .await?;
+            println!("Added {} token(s)", tokens.len());
+        }
+        TokensCommand::Generate { count, options } => {
+            let tokens: Vec<_> = (0..*count).map(|_| generate_token()).collect();
+            store
+                .add_tokens(&tokens, options.expires_at(), options.max_uses)
+                .await?;
+            for token in tokens {
+                println!("{token}");
+            }
+        }
+        TokensCommand::Revoke { token } => {
+            if !store.revoke_token(token).await? {
+                bail!("Token {token} does not exist or is already revoked");
+            }
+            println!("Revoked {token}");
+        }
+        TokensCommand::Unassign { user } => {
+            let freed = store.unassign_user(user).await?;
+            if freed == 0 {
+                bail!("User {user} does not have a token");
+            }
+            println!("Unassigned {freed} token(s) from {user}");
+        }
+    }
 
-    let auth_strategy = if let Some(tokens_file) = args.tokens_file {
+    Ok(())
+}
+
+async fn run_access(args: &Args, command: &AccessCommand) -> Result<()> {
+    let store = Store::connect(&args.data_dir()).await?;
+
+    match command {
+        AccessCommand::List => {
+            for (user, access) in store.list_access().await? {
+                println!("{user}\t{access}");
+            }
+        }
+        AccessCommand::Allow { user } => {
+            store.set_access(user, Access::Allow).await?;
+            println!("Allowed {user}");
+        }
+        AccessCommand::Ban { user } => {
+            store.set_access(user, Access::Ban).await?;
+            println!("Banned {user}");
+        }
+        AccessCommand::Remove { user } => {
+            if !store.remove_access(user).await? {
+                bail!("User {user} is neither allowed nor banned");
+            }
+            println!("Removed {user}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_usage(args: &Args, user: Option<&str>) -> Result<()> {
+    let store = Store::connect(&args.data_dir()).await?;
+    let totals = store.usage_totals(user).await?;
+
+    println!("{}", usage::report(&totals, &args.pricing()));
+
+    Ok(())
+}
+
+async fn run_discord(args: Args, backend: Arc<dyn ChatBackend>) -> Result<()> {
+    let store = Store::connect(&args.data_dir()).await?;
+    let pricing = args.pricing();
+    let limits = args.limits();
+
+    let fallback = if args.allowlist_only {
+        warn!("Starting with auth-strategy=ALLOWLIST_ONLY");
+        None
+    } else if let Some(guild_id) = args.guild_id {
+        warn!("Guild ID provided. Starting with auth-strategy=GUILD_ROLE");
+        Some(AuthenticationStrategy::GuildRole(GuildRoles::new(
+            guild_id,
+            args.role_ids.clone(),
+            Duration::from_secs(args.role_cache_secs),
+        )))
+    } else if let Some(tokens_file) = &args.tokens_file {
         warn!("Tokens file provided. Starting with auth-strategy=TOKEN_LIST");
-        let store = Store::connect(&args.data_dir.unwrap_or(DEFAULT_DATA_DIR.into())).await?;
-        store
-            .ensure_tokens(&read_tokens_file(&tokens_file)?)
-            .await?;
-        AuthenticationStrategy::TokenList(store)
+        store.ensure_tokens(&read_tokens_file(tokens_file)?).await?;
+        Some(AuthenticationStrategy::TokenList(store.clone()))
     } else {
         warn!("No tokens file provided. Starting with auth-strategy=ALLOW_ALL");
-        AuthenticationStrategy::NoAuthentication
+        Some(AuthenticationStrategy::NoAuthentication)
+    };
+
+    // Bans apply whichever strategy is in use
+    let auth_strategy = AuthenticationStrategy::AccessList(AccessList {
+        store: store.clone(),
+        fallback: fallback.map(Box::new),
+    });
+
+    let settings = Settings {
+        default_level: args.default_level,
+        retry_temperature: args.retry_temperature,
+        pricing,
+        limits,
+        admin_ids: args.admin_ids.iter().copied().map(UserId).collect(),
+        threads: args.thread_channel.map(|channel_id| ThreadSettings {
+            channel_id: ChannelId(channel_id),
+            private: !args.public_threads,
+        }),
     };
 
-    do_chat_bot(auth_strategy).await?;
+    do_chat_bot(auth_strategy, backend, store, settings).await?;
 
     Ok(())
 }
@@ -1,18 +1,89 @@
 use eyre::Result;
 use regex::Regex;
-use strum::{EnumIter, IntoEnumIterator};
+use std::sync::Arc;
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
 
-use crate::gpt::Conversation;
+use crate::{
+    gpt::{ChatBackend, Conversation, OnDelta, RequestOptions, Role, Usage},
+    store::Session,
+    usage::UsageKind,
+};
 
 const COMMAND_REGEX: &str = r"^!(\w+)\s+(.+)$";
 const NO_ARG_COMMAND_REGEX: &str = r"^!(\w+)$";
 
-const CONVERSATION_PROMPT: &str = "I am learning to speak Polish. You are a Polish teacher. Let's have a conversation at A2 level in Polish. Do not provide any translations.";
-const TEACH_PROMPT: &str = "I am learning to speak Polish. You are a Polish teacher. Please correct any grammar or mistakes I make in the following sentences, in English. Please only speak in English. Do not patronise me with complements.";
+// Prompt templates. `{language}` is replaced with the language being learned, and `{level}` with
+// the learner's CEFR level.
+const CONVERSATION_PROMPT: &str = "I am learning to speak {language}. You are a {language} teacher. Let's have a conversation at {level} level in {language}. Use vocabulary and grammar appropriate for a {level} learner. Do not provide any translations.";
+const TEACH_PROMPT: &str = "I am learning to speak {language}. You are a {language} teacher. My level is {level}. Please correct any grammar or mistakes I make in the following sentences, in English, focusing on what matters at {level} level. Please only speak in English. Do not patronise me with complements.";
+const REPLYING_TO_PROMPT: &str = "For context, I was replying to the message below. Take it into account when judging whether what I wrote makes sense, but do not correct it.";
 const DEFINE_PROMPT: &str =
-    "I am learning to speak Polish. You are a Polish teacher. What does this word mean?";
-const CASES_PROMPT: &str = "I am learning to speak Polish. You are a Polish teacher. Please provide me with all of the cases for the following Polish word.";
-const EXAMPLES_PROMPT: &str = "I am learning to speak Polish. You are a Polish teacher. Please provide me with 3 example sentences and translations containing the following Polish word.";
+    "I am learning to speak {language}. You are a {language} teacher. What does this word mean?";
+const CASES_PROMPT: &str = "I am learning to speak {language}. You are a {language} teacher. Please provide me with all of the cases for the following {language} word.";
+const EXAMPLES_PROMPT: &str = "I am learning to speak {language}. You are a {language} teacher. Please provide me with 3 example sentences and translations containing the following {language} word.";
+const TRANSLATE_PROMPT: &str = "I am learning to speak {language}. You are a {language} teacher. Please translate the following {language} text into English. Only reply with the translation.";
+const EXPLAIN_PROMPT: &str = "I am learning to speak {language}. You are a {language} teacher. My level is {level}. You gave me the following corrections to something I wrote. Please explain the grammar behind them in more detail, in English, with an example or two at {level} level.";
+
+/// A language that can be learned with Gnome Chompski
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString, EnumIter, sqlx::Type)]
+#[strum(ascii_case_insensitive)]
+#[sqlx(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    Polish,
+    German,
+    Spanish,
+    Ukrainian,
+    French,
+    Italian,
+    Portuguese,
+    Dutch,
+    Czech,
+}
+
+impl Language {
+    fn supported() -> String {
+        Language::iter()
+            .map(|language| language.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A CEFR proficiency level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString, EnumIter, sqlx::Type)]
+#[strum(ascii_case_insensitive)]
+pub enum Level {
+    A1,
+    #[default]
+    A2,
+    B1,
+    B2,
+    C1,
+    C2,
+}
+
+impl Level {
+    fn supported() -> String {
+        Level::iter()
+            .map(|level| level.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Drops all whitespace, for comparing replies with the Discord messages they were posted as.
+/// Splitting long replies over several messages doesn't always keep their spacing.
+fn squash(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Fills in the given prompt template for the learner's language and level
+fn prompt(template: &str, language: Language, level: Level) -> String {
+    template
+        .replace("{language}", &language.to_string())
+        .replace("{level}", &level.to_string())
+}
 
 #[derive(Debug, PartialEq, Eq, EnumIter)]
 pub enum Command {
@@ -21,7 +92,12 @@ pub enum Command {
     Define(String),
     Cases(String),
     Example(String),
+    Language(String),
+    Level(String),
+    Summary,
+    Usage,
     Undo,
+    Retry,
     Help,
     Unknown,
 }
@@ -33,34 +109,56 @@ impl Command {
             Regex::new(NO_ARG_COMMAND_REGEX).expect("implementation error - invalid regex");
 
         if let Some(cap) = cmd_regex.captures(s) {
-            let command = &cap[1];
-            let arg = &cap[2];
-
-            match command {
-                "chat" => Some(Command::Chat(arg.to_string())),
-                "ask" => Some(Command::Ask(arg.to_string())),
-                "def" => Some(Command::Define(arg.to_string())),
-                "cases" => Some(Command::Cases(arg.to_string())),
-                "ex" => Some(Command::Example(arg.to_string())),
-                _ => Some(Command::Unknown),
-            }
-        } else if let Some(cap) = no_arg_cmd_regex.captures(s) {
-            let command = &cap[1];
-
-            match command {
-                "undo" => Some(Command::Undo),
-                "help" => Some(Command::Help),
-                _ => Some(Command::Unknown),
-            }
+            Some(Command::from_name(&cap[1], Some(cap[2].to_string())))
         } else {
-            None
+            no_arg_cmd_regex
+                .captures(s)
+                .map(|cap| Command::from_name(&cap[1], None))
         }
     }
+
+    /// Builds the command with the given name, as used after the `!` or `/`
+    pub fn from_name(name: &str, arg: Option<String>) -> Command {
+        match (name, arg) {
+            ("chat", Some(arg)) => Command::Chat(arg),
+            ("ask", Some(arg)) => Command::Ask(arg),
+            ("def", Some(arg)) => Command::Define(arg),
+            ("cases", Some(arg)) => Command::Cases(arg),
+            ("ex", Some(arg)) => Command::Example(arg),
+            ("lang", Some(arg)) => Command::Language(arg),
+            ("level", Some(arg)) => Command::Level(arg),
+            ("summary", None) => Command::Summary,
+            ("usage", None) => Command::Usage,
+            ("undo", None) => Command::Undo,
+            ("retry", None) => Command::Retry,
+            ("help", None) => Command::Help,
+            _ => Command::Unknown,
+        }
+    }
+}
+
+/// One-click follow-ups to a reply, offered as buttons underneath it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, EnumIter)]
+#[strum(serialize_all = "lowercase")]
+pub enum FollowUp {
+    /// Forget the last message and reply
+    Undo,
+    /// Ask for a different reply to the last message, like `!retry`
+    Regenerate,
+    /// Translate a reply into English
+    Translate,
+    /// Go into more detail about a correction
+    Explain,
 }
 
 #[derive(Debug)]
 pub struct TeachBot {
+    backend: Arc<dyn ChatBackend>,
     conversation: Conversation,
+    language: Language,
+    level: Level,
+    /// Temperature to use when the user asks for a different reply, if it should differ
+    retry_temperature: Option<f32>,
 }
 
 pub struct MessageReply {
@@ -69,6 +167,12 @@ pub struct MessageReply {
 
     // What should be sent to the channel
     pub channel: Option<String>,
+
+    // Tokens spent on producing this reply
+    pub usage: Vec<(UsageKind, Usage)>,
+
+    // Whether the messages are part of the conversation, and can be followed up on
+    pub follow_ups: bool,
 }
 
 impl MessageReply {
@@ -76,6 +180,8 @@ impl MessageReply {
         Self {
             channel: Some(msg.into()),
             reply: None,
+            usage: vec![],
+            follow_ups: false,
         }
     }
 
@@ -83,6 +189,8 @@ impl MessageReply {
         Self {
             reply: Some(msg.into()),
             channel: None,
+            usage: vec![],
+            follow_ups: false,
         }
     }
 
@@ -90,33 +198,194 @@ impl MessageReply {
         Self {
             reply: Some(reply.into()),
             channel: Some(msg.into()),
+            usage: vec![],
+            follow_ups: false,
         }
     }
+
+    pub fn with_usage(mut self, kind: UsageKind, usage: Usage) -> Self {
+        self.usage.push((kind, usage));
+        self
+    }
+
+    pub fn with_follow_ups(mut self) -> Self {
+        self.follow_ups = true;
+        self
+    }
 }
 
 impl TeachBot {
+    pub fn new(backend: Arc<dyn ChatBackend>, level: Level) -> Self {
+        let language = Language::default();
+
+        Self {
+            conversation: Conversation::new(
+                backend.clone(),
+                prompt(CONVERSATION_PROMPT, language, level),
+            ),
+            backend,
+            language,
+            level,
+            retry_temperature: None,
+        }
+    }
+
+    /// Restores a bot from a previously saved session
+    pub fn from_session(backend: Arc<dyn ChatBackend>, session: Session) -> Self {
+        Self {
+            conversation: Conversation::from_history(
+                backend.clone(),
+                prompt(CONVERSATION_PROMPT, session.language, session.level),
+                session.history,
+                session.summary,
+            ),
+            backend,
+            language: session.language,
+            level: session.level,
+            retry_temperature: None,
+        }
+    }
+
+    /// Asks for different replies at the given temperature, so they're less likely to come out
+    /// the same
+    pub fn with_retry_temperature(mut self, retry_temperature: Option<f32>) -> Self {
+        self.retry_temperature = retry_temperature;
+        self
+    }
+
+    /// Snapshot of the bot's state, for persisting
+    pub fn session(&self) -> Session {
+        Session {
+            history: self.conversation.history().to_vec(),
+            language: self.language,
+            level: self.level,
+            summary: self.conversation.summary().map(str::to_string),
+        }
+    }
+
+    /// Fills in the given prompt template for this learner
+    fn prompt(&self, template: &str) -> String {
+        prompt(template, self.language, self.level)
+    }
+
+    /// Asks a one-off question using the given prompt template, without affecting the history
+    async fn ask(
+        &self,
+        kind: UsageKind,
+        template: &str,
+        message: String,
+    ) -> Result<(String, UsageKind, Usage)> {
+        let (reply, usage) =
+            Conversation::ask(self.backend.clone(), self.prompt(template), message).await?;
+        Ok((reply, kind, usage))
+    }
+
     pub async fn handle(&mut self, message: &str) -> Result<MessageReply> {
+        self.handle_streaming(message, None, &|_| {}).await
+    }
+
+    /// Like `handle`, but calls `on_delta` with each piece of the conversation's reply as it
+    /// arrives, so it can be shown to the user straight away. The final reply is still returned
+    /// as the `channel` message.
+    ///
+    /// `replying_to` is the bot's message that the user is replying to, if they picked one out.
+    /// Otherwise (or if it isn't part of the conversation), they're taken to be replying to the
+    /// latest reply in the conversation.
+    pub async fn handle_streaming(
+        &mut self,
+        message: &str,
+        replying_to: Option<&str>,
+        on_delta: &OnDelta,
+    ) -> Result<MessageReply> {
         if let Some(command) = Command::read(message) {
-            let msg = match command {
-                Command::Chat(new_prompt) => {
-                    self.conversation = Conversation::new(CONVERSATION_PROMPT);
-                    self.conversation.message(new_prompt).await
-                }
-                Command::Ask(question) => Conversation::ask(TEACH_PROMPT, question).await,
-                Command::Define(question) => Conversation::ask(DEFINE_PROMPT, question).await,
-                Command::Cases(word) => Conversation::ask(CASES_PROMPT, word).await,
-                Command::Example(word) => Conversation::ask(EXAMPLES_PROMPT, word).await,
-                Command::Undo => return self.undo_reply(),
-                Command::Help => return self.help_reply(),
-                Command::Unknown => return self.help_reply(),
-            }?;
-
-            Ok(MessageReply::channel(msg))
+            self.handle_command(command, on_delta).await
         } else {
-            self.chat_response(message).await
+            self.chat_response(message, replying_to, on_delta).await
         }
     }
 
+    /// Handles a command, whether it was typed out or came from a slash command
+    pub async fn handle_command(
+        &mut self,
+        command: Command,
+        on_delta: &OnDelta,
+    ) -> Result<MessageReply> {
+        let (msg, kind, usage) = match command {
+            Command::Chat(new_prompt) => {
+                self.reset_conversation();
+                let msg = self
+                    .conversation
+                    .message_streaming(new_prompt, on_delta)
+                    .await?;
+                return Ok(MessageReply::channel(msg)
+                    .with_usage(UsageKind::Conversation, self.conversation.take_usage())
+                    .with_follow_ups());
+            }
+            Command::Ask(question) => self.ask(UsageKind::Ask, TEACH_PROMPT, question).await?,
+            Command::Define(question) => {
+                self.ask(UsageKind::Define, DEFINE_PROMPT, question).await?
+            }
+            Command::Cases(word) => self.ask(UsageKind::Cases, CASES_PROMPT, word).await?,
+            Command::Example(word) => self.ask(UsageKind::Example, EXAMPLES_PROMPT, word).await?,
+            Command::Language(language) => return self.language_reply(&language),
+            Command::Level(level) => return self.level_reply(&level),
+            Command::Summary => return self.summary_reply(),
+            Command::Usage => {
+                return Ok(MessageReply::reply(
+                    "Sorry, I don't keep track of usage here.",
+                ))
+            }
+            Command::Undo => return self.undo_reply(),
+            Command::Retry => return self.retry_reply(on_delta).await,
+            Command::Help => return self.help_reply(),
+            Command::Unknown => return self.help_reply(),
+        };
+
+        Ok(MessageReply::channel(msg).with_usage(kind, usage))
+    }
+
+    /// Handles a click on one of the buttons under a reply. `message` is the text of the message
+    /// the button was on.
+    pub async fn handle_follow_up(
+        &mut self,
+        follow_up: FollowUp,
+        message: &str,
+        on_delta: &OnDelta,
+    ) -> Result<MessageReply> {
+        let is_latest = self.is_latest_reply(message);
+
+        let (msg, kind, usage) = match follow_up {
+            FollowUp::Undo if is_latest => return self.undo_reply(),
+            FollowUp::Regenerate if is_latest => return self.retry_reply(on_delta).await,
+            FollowUp::Undo | FollowUp::Regenerate => {
+                return Ok(MessageReply::reply(
+                    "The conversation has moved on since then, so I can only do that for my latest reply.",
+                ))
+            }
+            FollowUp::Translate => {
+                self.ask(UsageKind::Translate, TRANSLATE_PROMPT, message.to_string())
+                    .await?
+            }
+            FollowUp::Explain => {
+                self.ask(UsageKind::Explain, EXPLAIN_PROMPT, message.to_string())
+                    .await?
+            }
+        };
+
+        Ok(MessageReply::reply(msg).with_usage(kind, usage))
+    }
+
+    /// Whether `message` is (the end of) the latest reply in the conversation. Long replies are
+    /// split over several messages, and the buttons only go on the last of them.
+    fn is_latest_reply(&self, message: &str) -> bool {
+        let message = squash(message);
+        !message.is_empty()
+            && self
+                .conversation
+                .last_reply()
+                .is_some_and(|reply| squash(reply).ends_with(&message))
+    }
+
     fn help_reply(&self) -> Result<MessageReply> {
         let mut buf = String::new();
 
@@ -138,9 +407,28 @@ impl TeachBot {
                 Command::Example(_) => buf.push_str(
                     "- `!ex <word(s)>` Show 3 example sentences containing this word or phrase\n",
                 ),
+                Command::Language(_) => buf.push_str(&format!(
+                    "- `!lang <language>` Switch the language you're learning (currently {}). One of: {}\n",
+                    self.language,
+                    Language::supported()
+                )),
+                Command::Level(_) => buf.push_str(&format!(
+                    "- `!level <level>` Set your CEFR level (currently {}). One of: {}\n",
+                    self.level,
+                    Level::supported()
+                )),
+                Command::Summary => buf.push_str(
+                    "- `!summary` Show what Gnome Chompski remembers from earlier in the conversation\n",
+                ),
+                Command::Usage => buf.push_str(
+                    "- `!usage` Show how many tokens you've used chatting with Gnome Chompski\n",
+                ),
                 Command::Undo => {
                     buf.push_str("- `!undo` Remove the last message and reply from the chat history\n")
                 }
+                Command::Retry => buf.push_str(
+                    "- `!retry` Get a different reply to your last message, along with a fresh correction\n",
+                ),
                 Command::Help => buf.push_str("- `!help` Print this help message\n"),
                 Command::Unknown => {}
             }
@@ -149,6 +437,58 @@ impl TeachBot {
         Ok(MessageReply::reply(buf))
     }
 
+    fn reset_conversation(&mut self) {
+        self.conversation =
+            Conversation::new(self.backend.clone(), self.prompt(CONVERSATION_PROMPT));
+    }
+
+    fn language_reply(&mut self, language: &str) -> Result<MessageReply> {
+        match language.trim().parse::<Language>() {
+            Ok(language) => {
+                self.language = language;
+                self.reset_conversation();
+
+                Ok(MessageReply::reply(format!(
+                    "You're now learning {language}! Start a new conversation with `!chat <topic>`."
+                )))
+            }
+            Err(_) => Ok(MessageReply::reply(format!(
+                "Sorry, I don't speak {language}. I can help you learn one of: {}",
+                Language::supported()
+            ))),
+        }
+    }
+
+    fn level_reply(&mut self, level: &str) -> Result<MessageReply> {
+        match level.trim().parse::<Level>() {
+            Ok(level) => {
+                self.level = level;
+                // Keep the conversation going, but at the new level from here on
+                self.conversation
+                    .set_prompt(self.prompt(CONVERSATION_PROMPT));
+
+                Ok(MessageReply::reply(format!(
+                    "Got it! I'll talk to you at {level} level from now on."
+                )))
+            }
+            Err(_) => Ok(MessageReply::reply(format!(
+                "Sorry, {level} isn't a level I know. Please pick one of: {}",
+                Level::supported()
+            ))),
+        }
+    }
+
+    fn summary_reply(&self) -> Result<MessageReply> {
+        match self.conversation.summary() {
+            Some(summary) => Ok(MessageReply::reply(format!(
+                "Here's what I remember from earlier in our conversation:\n\n> {summary}"
+            ))),
+            None => Ok(MessageReply::reply(
+                "Our conversation is still short enough that I remember all of it!",
+            )),
+        }
+    }
+
     fn undo_reply(&mut self) -> Result<MessageReply> {
         let message_last = self.conversation.forget_last();
         let message_last_but_one = self.conversation.forget_last();
@@ -173,34 +513,103 @@ impl TeachBot {
         }
     }
 
-    async fn chat_response(&mut self, message: &str) -> Result<MessageReply> {
+    /// Replaces the last reply with a new one, and has another go at correcting the message it
+    /// was replying to
+    async fn retry_reply(&mut self, on_delta: &OnDelta) -> Result<MessageReply> {
+        let Some(message) = self
+            .conversation
+            .last_reply()
+            .and(self.conversation.last_message())
+            .map(str::to_string)
+        else {
+            return Ok(MessageReply::reply("There's no reply for me to retry."));
+        };
+        let teach_prompt = self.teach_prompt(self.conversation.last_message_context());
+
+        let options = RequestOptions {
+            temperature: self.retry_temperature,
+        };
         let (chat_response, teach_response) = tokio::join!(
-            self.conversation.message(message),
-            Self::fetch_teacher_thoughts(message)
+            self.conversation.regenerate(&options, on_delta),
+            Self::fetch_teacher_thoughts(self.backend.clone(), teach_prompt, &message)
         );
 
-        Ok(MessageReply::message_and_reply(
-            chat_response?,
-            teach_response?,
-        ))
+        let chat_response = chat_response?.expect("checked there was a reply");
+        let (teach_response, teach_usage) = teach_response?;
+
+        Ok(
+            MessageReply::message_and_reply(chat_response, teach_response)
+                .with_usage(UsageKind::Conversation, self.conversation.take_usage())
+                .with_usage(UsageKind::Correction, teach_usage)
+                .with_follow_ups(),
+        )
     }
 
-    async fn fetch_teacher_thoughts(message: &str) -> Result<String> {
-        Conversation::new(TEACH_PROMPT).message(message).await
+    /// The reply the user is answering: the one they picked out, if it's one of ours, or else the
+    /// latest one. Picked messages may only be part of a long reply, in which case the whole reply
+    /// is used.
+    fn correction_context(&self, replying_to: Option<&str>) -> Option<&str> {
+        let picked = replying_to.map(squash).filter(|picked| !picked.is_empty());
+        let picked_reply = picked.and_then(|picked| {
+            self.conversation
+                .history()
+                .iter()
+                .rev()
+                .filter(|message| message.role == Role::Assistant)
+                .find(|message| squash(&message.content).contains(&picked))
+                .map(|message| message.content.as_str())
+        });
+
+        picked_reply.or(self.conversation.last_reply())
     }
-}
 
-impl Default for TeachBot {
-    fn default() -> Self {
-        Self {
-            conversation: Conversation::new(CONVERSATION_PROMPT),
+    /// The prompt for correcting the user's message, given what they were replying to
+    fn teach_prompt(&self, replying_to: Option<&str>) -> String {
+        let teach_prompt = self.prompt(TEACH_PROMPT);
+
+        match replying_to {
+            Some(context) => format!("{teach_prompt} {REPLYING_TO_PROMPT}\n\n{context}"),
+            None => teach_prompt,
         }
     }
+
+    async fn chat_response(
+        &mut self,
+        message: &str,
+        replying_to: Option<&str>,
+        on_delta: &OnDelta,
+    ) -> Result<MessageReply> {
+        let teach_prompt = self.teach_prompt(self.correction_context(replying_to));
+
+        let (chat_response, teach_response) = tokio::join!(
+            self.conversation.message_streaming(message, on_delta),
+            Self::fetch_teacher_thoughts(self.backend.clone(), teach_prompt, message)
+        );
+
+        let chat_response = chat_response?;
+        let (teach_response, teach_usage) = teach_response?;
+
+        Ok(
+            MessageReply::message_and_reply(chat_response, teach_response)
+                .with_usage(UsageKind::Conversation, self.conversation.take_usage())
+                .with_usage(UsageKind::Correction, teach_usage)
+                .with_follow_ups(),
+        )
+    }
+
+    async fn fetch_teacher_thoughts(
+        backend: Arc<dyn ChatBackend>,
+        teach_prompt: String,
+        message: &str,
+    ) -> Result<(String, Usage)> {
+        Conversation::ask(backend, teach_prompt, message).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gpt::EchoBackend;
 
     #[test]
     fn test_parse_commands() {
@@ -221,7 +630,92 @@ mod tests {
             Command::read("!ex quux!"),
             Some(Command::Example("quux!".to_string()))
         );
+        assert_eq!(
+            Command::read("!lang German"),
+            Some(Command::Language("German".to_string()))
+        );
+        assert_eq!(
+            Command::read("!level b1"),
+            Some(Command::Level("b1".to_string()))
+        );
+        assert_eq!(Command::read("!summary"), Some(Command::Summary));
+        assert_eq!(Command::read("!usage"), Some(Command::Usage));
         assert_eq!(Command::read("!undo"), Some(Command::Undo));
+        assert_eq!(Command::read("!retry"), Some(Command::Retry));
+    }
+
+    #[test]
+    fn test_parse_languages() {
+        assert_eq!("german".parse::<Language>(), Ok(Language::German));
+        assert_eq!("UKRAINIAN".parse::<Language>(), Ok(Language::Ukrainian));
+        assert!("klingon".parse::<Language>().is_err());
+    }
+
+    #[test]
+    fn test_parse_levels() {
+        assert_eq!("b2".parse::<Level>(), Ok(Level::B2));
+        assert_eq!("C1".parse::<Level>(), Ok(Level::C1));
+        assert!("D1".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn test_prompts_use_language_and_level() {
+        assert_eq!(
+            prompt(DEFINE_PROMPT, Language::Spanish, Level::B1),
+            "I am learning to speak Spanish. You are a Spanish teacher. What does this word mean?"
+        );
+        assert!(prompt(CONVERSATION_PROMPT, Language::German, Level::C1)
+            .contains("a conversation at C1 level in German"));
+    }
+
+    #[tokio::test]
+    async fn test_corrections_know_what_is_being_replied_to() {
+        let mut bot = TeachBot::new(Arc::new(EchoBackend), Level::default());
+        assert!(!bot.teach_prompt(None).contains(REPLYING_TO_PROMPT));
+        assert!(bot
+            .teach_prompt(Some("Jak się masz?"))
+            .ends_with(&format!("{REPLYING_TO_PROMPT}\n\nJak się masz?")));
+
+        bot.handle("pierwsza").await.unwrap();
+        assert_eq!(bot.conversation.last_message_context(), None);
+        bot.handle("druga").await.unwrap();
+        assert_eq!(bot.conversation.last_message_context(), Some("pierwsza"));
+
+        // Earlier replies can be picked out..
+        assert_eq!(bot.correction_context(Some("pierwsza")), Some("pierwsza"));
+        // ..but anything that isn't one of ours falls back to the latest reply
+        assert_eq!(bot.correction_context(Some("a correction")), Some("druga"));
+        assert_eq!(bot.correction_context(Some(" ")), Some("druga"));
+        assert_eq!(bot.correction_context(None), Some("druga"));
+    }
+
+    #[tokio::test]
+    async fn test_follow_ups_only_undo_the_latest_reply() {
+        let mut bot = TeachBot::new(Arc::new(EchoBackend), Level::default());
+        bot.handle("pierwsza").await.unwrap();
+        bot.handle("druga").await.unwrap();
+
+        let reply = bot
+            .handle_follow_up(FollowUp::Undo, "pierwsza", &|_| {})
+            .await
+            .unwrap();
+        assert!(reply.reply.unwrap().contains("moved on"));
+        assert_eq!(bot.conversation.history().len(), 5);
+
+        let reply = bot
+            .handle_follow_up(FollowUp::Regenerate, "druga", &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(reply.channel.as_deref(), Some("druga"));
+        // Retrying corrects the message again, too
+        assert_eq!(reply.reply.as_deref(), Some("druga"));
+        assert!(reply.follow_ups);
+        assert_eq!(bot.conversation.history().len(), 5);
+
+        bot.handle_follow_up(FollowUp::Undo, "druga", &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(bot.conversation.last_reply(), Some("pierwsza"));
     }
 
     #[test]
@@ -0,0 +1,164 @@
+use eyre::Result;
+
+use crate::store::{Store, DAY_SECS};
+
+const MINUTE_SECS: i64 = 60;
+
+/// How much each user is allowed to talk to Gnome Chompski. `None` means there's no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub messages_per_minute: Option<u32>,
+    pub requests_per_day: Option<u32>,
+    pub tokens_per_day: Option<u32>,
+}
+
+/// Which limit a user has run into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    MessagesPerMinute,
+    RequestsPerDay,
+    TokensPerDay,
+}
+
+impl QuotaExceeded {
+    pub fn explanation(&self) -> &'static str {
+        match self {
+            QuotaExceeded::MessagesPerMinute => {
+                "Woah, slow down! Gnome Chompski can't think that fast. Please wait a minute before writing again."
+            }
+            QuotaExceeded::RequestsPerDay | QuotaExceeded::TokensPerDay => {
+                "Gnome Chompski is all talked out for today. Come back tomorrow and we can carry on!"
+            }
+        }
+    }
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// Checks the user's recent activity against the limits, as of `now` (in seconds). Windows
+    /// are rolling, so a day is the 24 hours leading up to `now`.
+    pub async fn check(
+        &self,
+        store: &Store,
+        user_id: &str,
+        now: i64,
+    ) -> Result<Option<QuotaExceeded>> {
+        if let Some(limit) = self.messages_per_minute {
+            if store.messages_since(user_id, now - MINUTE_SECS).await? >= limit {
+                return Ok(Some(QuotaExceeded::MessagesPerMinute));
+            }
+        }
+
+        if let Some(limit) = self.requests_per_day {
+            if store.messages_since(user_id, now - DAY_SECS).await? >= limit {
+                return Ok(Some(QuotaExceeded::RequestsPerDay));
+            }
+        }
+
+        if let Some(limit) = self.tokens_per_day {
+            if store.tokens_since(user_id, now - DAY_SECS).await? >= limit {
+                return Ok(Some(QuotaExceeded::TokensPerDay));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpt::Usage, store::now_secs, usage::UsageKind};
+
+    #[tokio::test]
+    async fn test_unlimited_by_default() {
+        let store = Store::in_memory().await.unwrap();
+        store.record_message("123").await.unwrap();
+
+        assert!(Limits::default().is_unlimited());
+        assert_eq!(
+            Limits::default()
+                .check(&store, "123", now_secs())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_limits_roll_over() {
+        let store = Store::in_memory().await.unwrap();
+        let limits = Limits {
+            messages_per_minute: Some(2),
+            requests_per_day: Some(3),
+            ..Limits::default()
+        };
+        let now = now_secs();
+
+        store.record_message("123").await.unwrap();
+        assert_eq!(limits.check(&store, "123", now).await.unwrap(), None);
+
+        store.record_message("123").await.unwrap();
+        assert_eq!(
+            limits.check(&store, "123", now).await.unwrap(),
+            Some(QuotaExceeded::MessagesPerMinute)
+        );
+        // Other users have their own quota
+        assert_eq!(limits.check(&store, "456", now).await.unwrap(), None);
+
+        // A minute later, only the daily limit is left to worry about
+        assert_eq!(
+            limits
+                .check(&store, "123", now + MINUTE_SECS + 1)
+                .await
+                .unwrap(),
+            None
+        );
+        store.record_message("123").await.unwrap();
+        assert_eq!(
+            limits
+                .check(&store, "123", now + MINUTE_SECS + 1)
+                .await
+                .unwrap(),
+            Some(QuotaExceeded::RequestsPerDay)
+        );
+        assert_eq!(
+            limits
+                .check(&store, "123", now + DAY_SECS + 1)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_limit() {
+        let store = Store::in_memory().await.unwrap();
+        let limits = Limits {
+            tokens_per_day: Some(100),
+            ..Limits::default()
+        };
+        let usage = Usage {
+            prompt_tokens: 40,
+            completion_tokens: 20,
+        };
+
+        store
+            .record_usage("123", UsageKind::Conversation, usage)
+            .await
+            .unwrap();
+        assert_eq!(limits.check(&store, "123", now_secs()).await.unwrap(), None);
+
+        store
+            .record_usage("123", UsageKind::Correction, usage)
+            .await
+            .unwrap();
+        assert_eq!(
+            limits.check(&store, "123", now_secs()).await.unwrap(),
+            Some(QuotaExceeded::TokensPerDay)
+        );
+    }
+}
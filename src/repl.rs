@@ -0,0 +1,56 @@
+use eyre::Result;
+use inquire::{InquireError, Text};
+use log::error;
+use std::sync::Arc;
+
+use crate::{
+    gpt::ChatBackend,
+    model::{Level, MessageReply, TeachBot},
+};
+
+/// Chats with Gnome Chompski from the terminal, without needing a Discord bot.
+pub async fn do_repl(
+    backend: Arc<dyn ChatBackend>,
+    default_level: Level,
+    retry_temperature: Option<f32>,
+) -> Result<()> {
+    let mut bot = TeachBot::new(backend, default_level).with_retry_temperature(retry_temperature);
+
+    println!(
+        "Chatting with Gnome Chompski. Send `!help` to see the commands, or press Esc to quit.\n"
+    );
+
+    loop {
+        let message = match Text::new("you:").prompt() {
+            Ok(message) => message,
+            Err(InquireError::OperationCanceled | InquireError::OperationInterrupted) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let message = message.trim();
+        if message.is_empty() {
+            continue;
+        }
+
+        match bot.handle(message).await {
+            Ok(MessageReply { reply, channel, .. }) => {
+                // Mirror Discord, where the reply is attached to your message and comes first
+                if let Some(reply) = reply {
+                    println!("\n[reply]\n{reply}\n");
+                }
+
+                if let Some(channel) = channel {
+                    println!("\n[gnome]\n{channel}\n");
+                }
+            }
+            Err(e) => {
+                error!("Error while interacting with bot: {e:?}");
+                println!(
+                    "\n_Something went wrong whilst communicating with Gnome Chompski: {e}_\n"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
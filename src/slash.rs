@@ -0,0 +1,165 @@
+use serde_json::Value;
+use serenity::{
+    builder::CreateApplicationCommands,
+    model::application::{
+        command::CommandOptionType, interaction::application_command::ApplicationCommandInteraction,
+    },
+};
+use strum::IntoEnumIterator;
+
+use crate::model::{Command, Language, Level};
+
+/// A slash command, mirroring one of the `!` commands
+struct SlashCommand {
+    name: &'static str,
+    description: &'static str,
+    option: Option<SlashOption>,
+}
+
+/// The single argument a slash command takes
+struct SlashOption {
+    name: &'static str,
+    description: &'static str,
+    /// The only values allowed, if they're limited
+    choices: Option<fn() -> Vec<String>>,
+}
+
+const fn text_option(name: &'static str, description: &'static str) -> Option<SlashOption> {
+    Some(SlashOption {
+        name,
+        description,
+        choices: None,
+    })
+}
+
+const SLASH_COMMANDS: &[SlashCommand] = &[
+    SlashCommand {
+        name: "chat",
+        description: "Start a new conversation",
+        option: text_option("topic", "What to talk about"),
+    },
+    SlashCommand {
+        name: "ask",
+        description: "Ask the teacher a question, without affecting the conversation",
+        option: text_option("question", "What you'd like to know"),
+    },
+    SlashCommand {
+        name: "def",
+        description: "Define or translate a word or phrase",
+        option: text_option("word", "The word or phrase to define"),
+    },
+    SlashCommand {
+        name: "cases",
+        description: "List the cases of a word",
+        option: text_option("word", "The word to decline"),
+    },
+    SlashCommand {
+        name: "ex",
+        description: "Show some example sentences using a word",
+        option: text_option("word", "The word to use"),
+    },
+    SlashCommand {
+        name: "lang",
+        description: "Switch the language you're learning",
+        option: Some(SlashOption {
+            name: "language",
+            description: "The language to learn",
+            choices: Some(|| Language::iter().map(|l| l.to_string()).collect()),
+        }),
+    },
+    SlashCommand {
+        name: "level",
+        description: "Set your CEFR level",
+        option: Some(SlashOption {
+            name: "level",
+            description: "Your level",
+            choices: Some(|| Level::iter().map(|l| l.to_string()).collect()),
+        }),
+    },
+    SlashCommand {
+        name: "summary",
+        description: "Show what Gnome Chompski remembers from earlier in the conversation",
+        option: None,
+    },
+    SlashCommand {
+        name: "usage",
+        description: "Show how many tokens you've used",
+        option: None,
+    },
+    SlashCommand {
+        name: "undo",
+        description: "Forget the last message and reply",
+        option: None,
+    },
+    SlashCommand {
+        name: "retry",
+        description: "Get a different reply to your last message",
+        option: None,
+    },
+    SlashCommand {
+        name: "help",
+        description: "List the commands Gnome Chompski understands",
+        option: None,
+    },
+];
+
+/// Adds every slash command, so Discord can offer them to users
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    for slash in SLASH_COMMANDS {
+        commands.create_application_command(|command| {
+            command
+                .name(slash.name)
+                .description(slash.description)
+                .dm_permission(true);
+
+            if let Some(option) = &slash.option {
+                command.create_option(|o| {
+                    o.name(option.name)
+                        .description(option.description)
+                        .kind(CommandOptionType::String)
+                        .required(true);
+
+                    for choice in option.choices.map(|choices| choices()).unwrap_or_default() {
+                        o.add_string_choice(&choice, &choice);
+                    }
+                    o
+                });
+            }
+
+            command
+        });
+    }
+
+    commands
+}
+
+/// Works out which command the user picked
+pub fn read_command(interaction: &ApplicationCommandInteraction) -> Command {
+    let arg = interaction
+        .data
+        .options
+        .first()
+        .and_then(|option| option.value.as_ref())
+        .and_then(Value::as_str)
+        .map(String::from);
+
+    Command::from_name(&interaction.data.name, arg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slash_commands_match_commands() {
+        for slash in SLASH_COMMANDS {
+            let arg = slash.option.as_ref().map(|_| "foo".to_string());
+            assert_ne!(
+                Command::from_name(slash.name, arg),
+                Command::Unknown,
+                "/{} isn't a command",
+                slash.name
+            );
+        }
+    }
+}
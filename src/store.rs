@@ -1,36 +1,182 @@
 use eyre::{bail, Result};
-use futures::TryStreamExt;
 use log::warn;
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqlitePoolOptions},
-    ConnectOptions, SqlitePool,
+    SqlitePool,
 };
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs, path::Path};
 
+use crate::{
+    authentication::Access,
+    gpt::{MessageContent, Usage},
+    model::{Language, Level},
+    usage::UsageKind,
+};
+
 const STORE_NAME: &str = "store.db";
 
-async fn create_db_and_mk_tables(conn_string: &str) -> Result<()> {
-    let mut conn = SqliteConnectOptions::from_str(conn_string)?
-        .create_if_missing(true)
-        .connect()
-        .await?;
+/// Conditions under which the token `t` can be redeemed, given the current time
+const REDEEMABLE: &str = "
+    t.revoked = 0
+    AND (t.expires_at IS NULL OR t.expires_at > ?)
+    AND (SELECT COUNT(*) FROM token_redemptions r WHERE r.token = t.token) < t.max_uses
+";
+
+/// Schema migrations, applied in order on every connect. Each one only ever runs once against a
+/// given DB, so never edit or reorder these - add a new one to the end instead.
+///
+/// The first migrations use `IF NOT EXISTS`, as they pre-date this list and may already have
+/// been applied to DBs in the wild.
+const MIGRATIONS: &[&str] = &[
+    // 1: Auth tokens
+    "
+    CREATE TABLE IF NOT EXISTS tokens (
+        token TEXT PRIMARY KEY,
+        user_id INTEGER
+    );
+    ",
+    // 2: User sessions
+    "
+    CREATE TABLE IF NOT EXISTS sessions (
+        user_id TEXT PRIMARY KEY,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS session_messages (
+        user_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        PRIMARY KEY (user_id, position)
+    );
+    ",
+    // 3: Per-user target language
+    "ALTER TABLE sessions ADD COLUMN language TEXT;",
+    // 4: Per-user CEFR level
+    "ALTER TABLE sessions ADD COLUMN level TEXT;",
+    // 5: Summaries of older parts of the conversation
+    "ALTER TABLE sessions ADD COLUMN summary TEXT;",
+    // 6: Token usage
+    "
+    CREATE TABLE usage (
+        user_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX usage_by_user ON usage (user_id, created_at);
+    ",
+    // 7: Messages handled, for rate limiting
+    "
+    CREATE TABLE messages (
+        user_id TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX messages_by_user ON messages (user_id, created_at);
+    ",
+    // 8: Expiring, multi-use and revocable tokens. Redemptions move to their own table, so
+    // `tokens.user_id` is no longer used.
+    "
+    ALTER TABLE tokens ADD COLUMN expires_at INTEGER;
+    ALTER TABLE tokens ADD COLUMN max_uses INTEGER NOT NULL DEFAULT 1;
+    ALTER TABLE tokens ADD COLUMN revoked INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE token_redemptions (
+        token TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        redeemed_at INTEGER NOT NULL,
+        PRIMARY KEY (token, user_id)
+    );
+    CREATE INDEX token_redemptions_by_user ON token_redemptions (user_id);
+    INSERT INTO token_redemptions (token, user_id, redeemed_at)
+        SELECT token, CAST(user_id AS TEXT), 0 FROM tokens WHERE user_id IS NOT NULL;
+    ",
+    // 9: Allowed and banned users
+    "
+    CREATE TABLE access_list (
+        user_id TEXT PRIMARY KEY,
+        access TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    ",
+    // 10: Threads started for learners in server-channel mode
+    "
+    CREATE TABLE threads (
+        thread_id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX threads_by_user ON threads (user_id, created_at);
+    ",
+];
 
-    warn!("Creating table..");
+/// Brings the DB up to date by applying any migrations it hasn't seen yet. Returns the resulting
+/// schema version.
+async fn migrate(pool: &SqlitePool) -> Result<i64> {
     sqlx::query(
         "
-        CREATE TABLE IF NOT EXISTS tokens (
-            token TEXT PRIMARY KEY,
-            user_id INTEGER
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at INTEGER NOT NULL
         )
         ",
     )
-    .execute(&mut conn)
+    .execute(pool)
     .await?;
 
-    Ok(())
+    let current: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+
+    let mut version = current;
+    for migration in MIGRATIONS.iter().skip(current as usize) {
+        version += 1;
+        warn!("Applying DB migration {version}..");
+
+        let mut tx_conn = pool.begin().await?;
+
+        sqlx::query(migration).execute(&mut tx_conn).await?;
+        sqlx::query("INSERT INTO schema_version (version, applied_at) VALUES(?, ?)")
+            .bind(version)
+            .bind(now_secs())
+            .execute(&mut tx_conn)
+            .await?;
+
+        tx_conn.commit().await?;
+    }
+
+    Ok(version)
 }
 
+pub(crate) const DAY_SECS: i64 = 24 * 60 * 60;
+
+pub(crate) fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// A user's chat session, as persisted in the DB.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    pub history: Vec<MessageContent>,
+    pub language: Language,
+    pub level: Level,
+    pub summary: Option<String>,
+}
+
+/// Total usage for a user, of a specific kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageTotal {
+    pub user_id: String,
+    pub kind: UsageKind,
+    pub requests: i64,
+    pub usage: Usage,
+}
+
+#[derive(Clone)]
 pub struct Store {
     pool: SqlitePool,
 }
@@ -51,68 +197,79 @@ impl Store {
         let conn_string = format!("sqlite://{}", db_path.to_string_lossy());
 
         let is_new_db = !db_path.exists();
-        if !db_path.exists() {
-            create_db_and_mk_tables(&conn_string).await?;
-        }
 
         warn!("Creating DB pool {conn_string} (new? {is_new_db})");
-        let pool = SqlitePoolOptions::new().connect(&conn_string).await?;
+        let pool = SqlitePoolOptions::new()
+            .connect_with(SqliteConnectOptions::from_str(&conn_string)?.create_if_missing(true))
+            .await?;
+
+        let version = migrate(&pool).await?;
+        warn!("DB is at schema version {version}");
 
         Ok(Self { pool })
     }
 
-    /// Checks whether the user is already allocated
-    pub async fn has_allocated_token(&self, user_id: &str) -> Result<bool> {
-        let mut results = sqlx::query("SELECT * FROM tokens WHERE user_id = ?")
-            .bind(user_id)
-            .fetch(&self.pool);
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Self> {
+        // Each connection to an in-memory DB gets its own DB, so only ever use one
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
 
-        if (results.try_next().await?).is_some() {
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+        migrate(&pool).await?;
+
+        Ok(Self { pool })
     }
 
-    /// Checks whether a provided token is valid and not allocated
-    pub async fn is_token_valid(&self, token: &str) -> Result<bool> {
-        let mut results = sqlx::query_as::<_, TokenEntry>("SELECT * FROM tokens WHERE token = ?")
-            .bind(token)
-            .fetch(&self.pool);
+    /// Checks whether the user has redeemed a token that hasn't since been revoked
+    pub async fn has_allocated_token(&self, user_id: &str) -> Result<bool> {
+        Ok(sqlx::query_scalar(
+            "
+            SELECT EXISTS(
+                SELECT 1 FROM token_redemptions r JOIN tokens t ON t.token = r.token
+                WHERE r.user_id = ? AND t.revoked = 0
+            )
+            ",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?)
+    }
 
-        if let Some(entry) = results.try_next().await? {
-            Ok(entry.user_id.is_none())
-        } else {
-            Ok(false)
-        }
+    /// Checks whether a provided token exists and can still be redeemed
+    pub async fn is_token_valid(&self, token: &str) -> Result<bool> {
+        Ok(sqlx::query_scalar(&format!(
+            "SELECT EXISTS(SELECT 1 FROM tokens t WHERE t.token = ? AND {REDEEMABLE})"
+        ))
+        .bind(token)
+        .bind(now_secs())
+        .fetch_one(&self.pool)
+        .await?)
     }
 
-    /// Allocates a given user to a specific token
+    /// Allocates a given user to a specific token, using up one of its redemptions
     pub async fn allocate(&self, user_id: &str, token: &str) -> Result<()> {
-        let mut tx_conn = self.pool.begin().await?;
+        let now = now_secs();
 
-        {
-            let mut results =
-                sqlx::query_as::<_, TokenEntry>("SELECT * FROM tokens WHERE token = ?")
-                    .bind(token)
-                    .fetch(&mut tx_conn);
-
-            if let Some(entry) = results.try_next().await? {
-                if entry.user_id.is_some() {
-                    bail!("Token is already allocated");
-                }
-            } else {
-                bail!("Token is invalid");
-            }
-        }
+        // Checking and redeeming in one statement means two users can't race for the last use
+        let result = sqlx::query(&format!(
+            "
+            INSERT INTO token_redemptions (token, user_id, redeemed_at)
+            SELECT t.token, ?, ? FROM tokens t WHERE t.token = ? AND {REDEEMABLE}
+            "
+        ))
+        .bind(user_id)
+        .bind(now)
+        .bind(token)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
 
-        sqlx::query("UPDATE tokens SET user_id = ? WHERE token = ?")
-            .bind(user_id)
-            .bind(token)
-            .execute(&mut tx_conn)
-            .await?;
+        if result.rows_affected() == 0 {
+            bail!("Token is invalid, expired or used up");
+        }
 
-        tx_conn.commit().await?;
         Ok(())
     }
 
@@ -120,21 +277,622 @@ impl Store {
     /// not overwrite allocations.
     pub async fn ensure_tokens(&self, tokens: &[String]) -> Result<()> {
         warn!("Ensuring {} tokens are in the DB..", tokens.len());
+        self.add_tokens(tokens, None, 1).await
+    }
 
+    /// Adds tokens that can be redeemed by up to `max_uses` users before `expires_at` (in seconds
+    /// since the epoch). Tokens that already exist are left as they are.
+    pub async fn add_tokens(
+        &self,
+        tokens: &[String],
+        expires_at: Option<i64>,
+        max_uses: u32,
+    ) -> Result<()> {
         for token in tokens {
-            sqlx::query("INSERT OR IGNORE INTO tokens (token) VALUES(?)")
-                .bind(token)
-                .execute(&self.pool)
+            sqlx::query(
+                "INSERT OR IGNORE INTO tokens (token, expires_at, max_uses) VALUES(?, ?, ?)",
+            )
+            .bind(token)
+            .bind(expires_at)
+            .bind(max_uses)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Lists all tokens, along with who redeemed them
+    pub async fn list_tokens(&self) -> Result<Vec<TokenEntry>> {
+        let rows = sqlx::query_as::<_, TokenRow>(
+            "
+            SELECT t.token, t.expires_at, t.max_uses, t.revoked, GROUP_CONCAT(r.user_id) AS user_ids
+            FROM tokens t LEFT JOIN token_redemptions r ON r.token = t.token
+            GROUP BY t.token
+            ORDER BY t.token
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(TokenEntry::from).collect())
+    }
+
+    /// Revokes a token, so that it can no longer be redeemed and anyone who redeemed it has to
+    /// authenticate again. Returns whether there was a token to revoke - `false` if it doesn't exist
+    /// or was already revoked.
+    pub async fn revoke_token(&self, token: &str) -> Result<bool> {
+        let result = sqlx::query("UPDATE tokens SET revoked = 1 WHERE token = ? AND revoked = 0")
+            .bind(token)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Frees up any tokens redeemed by the user, so that they can be used again. Returns how
+    /// many tokens were freed up.
+    pub async fn unassign_user(&self, user_id: &str) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM token_redemptions WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Whether the user has been explicitly allowed or banned
+    pub async fn access(&self, user_id: &str) -> Result<Option<Access>> {
+        Ok(
+            sqlx::query_scalar("SELECT access FROM access_list WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    /// Allows or bans the user, replacing whatever was there before
+    pub async fn set_access(&self, user_id: &str, access: Access) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO access_list (user_id, access, updated_at) VALUES(?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(access)
+        .bind(now_secs())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Takes the user off the allow or ban list. Returns whether they were on it.
+    pub async fn remove_access(&self, user_id: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM access_list WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Lists everyone who has been allowed or banned
+    pub async fn list_access(&self) -> Result<Vec<(String, Access)>> {
+        Ok(
+            sqlx::query_as("SELECT user_id, access FROM access_list ORDER BY user_id")
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    /// Lists everyone who has chatted, whether directly or in a thread
+    pub async fn session_user_ids(&self) -> Result<Vec<String>> {
+        Ok(sqlx::query_scalar(
+            "
+            SELECT user_id FROM sessions WHERE user_id NOT LIKE 'thread:%'
+            UNION
+            SELECT user_id FROM threads
+            ORDER BY user_id
+            ",
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    /// Records that a thread was started for the user
+    pub async fn add_thread(&self, thread_id: &str, user_id: &str) -> Result<()> {
+        sqlx::query("INSERT INTO threads (thread_id, user_id, created_at) VALUES(?, ?, ?)")
+            .bind(thread_id)
+            .bind(user_id)
+            .bind(now_secs())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Who the thread was started for, if it's one of ours
+    pub async fn thread_owner(&self, thread_id: &str) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_scalar("SELECT user_id FROM threads WHERE thread_id = ?")
+                .bind(thread_id)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    /// The most recent thread started for the user, if any
+    pub async fn user_thread(&self, user_id: &str) -> Result<Option<String>> {
+        Ok(sqlx::query_scalar(
+            "SELECT thread_id FROM threads WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    /// Loads the user's previous session, if they have one
+    pub async fn load_session(&self, user_id: &str) -> Result<Option<Session>> {
+        let settings: Option<(Option<Language>, Option<Level>, Option<String>)> =
+            sqlx::query_as("SELECT language, level, summary FROM sessions WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
                 .await?;
+
+        let Some((language, level, summary)) = settings else {
+            return Ok(None);
+        };
+
+        let history = sqlx::query_as::<_, MessageContent>(
+            "SELECT role, content FROM session_messages WHERE user_id = ? ORDER BY position",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(Session {
+            history,
+            // Sessions saved before these settings existed were always Polish at A2 level
+            language: language.unwrap_or_default(),
+            level: level.unwrap_or_default(),
+            summary,
+        }))
+    }
+
+    /// Saves the user's session, replacing whatever was there before
+    pub async fn save_session(&self, user_id: &str, session: &Session) -> Result<()> {
+        let mut tx_conn = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO sessions (user_id, updated_at, language, level, summary) VALUES(?, ?, ?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(now_secs())
+        .bind(session.language)
+        .bind(session.level)
+        .bind(&session.summary)
+        .execute(&mut tx_conn)
+        .await?;
+
+        sqlx::query("DELETE FROM session_messages WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut tx_conn)
+            .await?;
+
+        for (position, message) in session.history.iter().enumerate() {
+            sqlx::query(
+                "INSERT INTO session_messages (user_id, position, role, content) VALUES(?, ?, ?, ?)",
+            )
+            .bind(user_id)
+            .bind(position as i64)
+            .bind(&message.role)
+            .bind(&message.content)
+            .execute(&mut tx_conn)
+            .await?;
         }
 
+        tx_conn.commit().await?;
         Ok(())
     }
 }
 
-#[derive(Debug, sqlx::FromRow)]
-#[allow(dead_code)]
-struct TokenEntry {
+impl Store {
+    /// Records tokens spent on behalf of a user
+    pub async fn record_usage(&self, user_id: &str, kind: UsageKind, usage: Usage) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO usage (user_id, kind, prompt_tokens, completion_tokens, created_at) VALUES(?, ?, ?, ?, ?)",
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(usage.prompt_tokens)
+        .bind(usage.completion_tokens)
+        .bind(now_secs())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Totals up usage per user and kind, either for everyone or for a single user
+    pub async fn usage_totals(&self, user_id: Option<&str>) -> Result<Vec<UsageTotal>> {
+        let rows: Vec<(String, UsageKind, i64, u32, u32)> = sqlx::query_as(
+            "
+            SELECT user_id, kind, COUNT(*), SUM(prompt_tokens), SUM(completion_tokens)
+            FROM usage
+            WHERE ?1 IS NULL OR user_id = ?1
+            GROUP BY user_id, kind
+            ORDER BY user_id, kind
+            ",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(user_id, kind, requests, prompt_tokens, completion_tokens)| UsageTotal {
+                    user_id,
+                    kind,
+                    requests,
+                    usage: Usage {
+                        prompt_tokens,
+                        completion_tokens,
+                    },
+                },
+            )
+            .collect())
+    }
+
+    /// Counts the tokens a user has spent since the given time
+    pub async fn tokens_since(&self, user_id: &str, since: i64) -> Result<u32> {
+        let tokens: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0) FROM usage WHERE user_id = ? AND created_at > ?",
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(tokens as u32)
+    }
+
+    /// Records that a message from the user is being handled
+    pub async fn record_message(&self, user_id: &str) -> Result<()> {
+        sqlx::query("INSERT INTO messages (user_id, created_at) VALUES(?, ?)")
+            .bind(user_id)
+            .bind(now_secs())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Counts the messages handled for everyone since the given time
+    pub async fn all_messages_since(&self, since: i64) -> Result<u32> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE created_at > ?")
+            .bind(since)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u32)
+    }
+
+    /// Counts the tokens everyone has spent since the given time
+    pub async fn all_tokens_since(&self, since: i64) -> Result<u32> {
+        let tokens: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0) FROM usage WHERE created_at > ?",
+        )
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(tokens as u32)
+    }
+
+    /// Counts the messages handled for a user since the given time
+    pub async fn messages_since(&self, user_id: &str, since: i64) -> Result<u32> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM messages WHERE user_id = ? AND created_at > ?",
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count as u32)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TokenEntry {
+    pub token: String,
+    /// When the token can no longer be redeemed, in seconds since the epoch
+    pub expires_at: Option<i64>,
+    /// How many users can redeem the token
+    pub max_uses: u32,
+    pub revoked: bool,
+    /// Users who have redeemed the token
+    pub user_ids: Vec<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct TokenRow {
     token: String,
-    user_id: Option<String>,
+    expires_at: Option<i64>,
+    max_uses: u32,
+    revoked: bool,
+    /// Comma-separated
+    user_ids: Option<String>,
+}
+
+impl From<TokenRow> for TokenEntry {
+    fn from(row: TokenRow) -> Self {
+        Self {
+            token: row.token,
+            expires_at: row.expires_at,
+            max_uses: row.max_uses,
+            revoked: row.revoked,
+            user_ids: row
+                .user_ids
+                .map(|ids| ids.split(',').map(String::from).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl TokenEntry {
+    /// Describes whether the token can still be redeemed
+    pub fn status(&self, now: i64) -> &'static str {
+        if self.revoked {
+            "revoked"
+        } else if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            "expired"
+        } else if self.user_ids.len() >= self.max_uses as usize {
+            "used up"
+        } else {
+            "active"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::Role;
+
+    #[tokio::test]
+    async fn test_migrations_are_applied_once() {
+        let store = Store::in_memory().await.unwrap();
+        assert_eq!(migrate(&store.pool).await.unwrap(), MIGRATIONS.len() as i64);
+
+        let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM schema_version")
+            .fetch_one(&store.pool)
+            .await
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn test_migrations_keep_existing_tokens() {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        // A DB created before migrations existed
+        sqlx::query("CREATE TABLE tokens (token TEXT PRIMARY KEY, user_id INTEGER)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO tokens (token, user_id) VALUES('abc', '123')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        migrate(&pool).await.unwrap();
+
+        let store = Store { pool };
+        assert!(store.has_allocated_token("123").await.unwrap());
+        assert_eq!(store.load_session("123").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_token_management() {
+        let store = Store::in_memory().await.unwrap();
+        store
+            .ensure_tokens(&["abc".to_string(), "def".to_string()])
+            .await
+            .unwrap();
+        store.allocate("123", "abc").await.unwrap();
+
+        assert_eq!(
+            store.list_tokens().await.unwrap(),
+            vec![
+                TokenEntry {
+                    token: "abc".to_string(),
+                    expires_at: None,
+                    max_uses: 1,
+                    revoked: false,
+                    user_ids: vec!["123".to_string()],
+                },
+                TokenEntry {
+                    token: "def".to_string(),
+                    expires_at: None,
+                    max_uses: 1,
+                    revoked: false,
+                    user_ids: vec![],
+                },
+            ]
+        );
+
+        assert_eq!(store.unassign_user("123").await.unwrap(), 1);
+        assert!(!store.has_allocated_token("123").await.unwrap());
+        assert!(store.is_token_valid("abc").await.unwrap());
+
+        assert!(store.revoke_token("def").await.unwrap());
+        assert!(!store.revoke_token("def").await.unwrap());
+        assert!(!store.is_token_valid("def").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_multi_use_tokens() {
+        let store = Store::in_memory().await.unwrap();
+        store
+            .add_tokens(&["class".to_string()], None, 2)
+            .await
+            .unwrap();
+
+        store.allocate("1", "class").await.unwrap();
+        store.allocate("2", "class").await.unwrap();
+        assert!(!store.is_token_valid("class").await.unwrap());
+        assert!(store.allocate("3", "class").await.is_err());
+
+        let entry = &store.list_tokens().await.unwrap()[0];
+        assert_eq!(entry.user_ids, vec!["1", "2"]);
+        assert_eq!(entry.status(now_secs()), "used up");
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens() {
+        let store = Store::in_memory().await.unwrap();
+        store
+            .add_tokens(&["old".to_string()], Some(now_secs() - 1), 1)
+            .await
+            .unwrap();
+
+        assert!(!store.is_token_valid("old").await.unwrap());
+        assert!(store.allocate("123", "old").await.is_err());
+        assert_eq!(
+            store.list_tokens().await.unwrap()[0].status(now_secs()),
+            "expired"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoking_deauthenticates() {
+        let store = Store::in_memory().await.unwrap();
+        store
+            .add_tokens(&["class".to_string()], None, 10)
+            .await
+            .unwrap();
+        store.allocate("123", "class").await.unwrap();
+        assert!(store.has_allocated_token("123").await.unwrap());
+
+        store.revoke_token("class").await.unwrap();
+        assert!(!store.has_allocated_token("123").await.unwrap());
+        assert!(!store.is_token_valid("class").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_round_trip() {
+        let store = Store::in_memory().await.unwrap();
+        assert_eq!(store.load_session("123").await.unwrap(), None);
+
+        let mut session = Session {
+            history: vec![
+                MessageContent {
+                    role: Role::System,
+                    content: "prompt".to_string(),
+                },
+                MessageContent {
+                    role: Role::User,
+                    content: "cześć".to_string(),
+                },
+                MessageContent {
+                    role: Role::Assistant,
+                    content: "dzień dobry".to_string(),
+                },
+            ],
+            language: Language::German,
+            level: Level::B2,
+            summary: Some("We talked about cats".to_string()),
+        };
+        store.save_session("123", &session).await.unwrap();
+        assert_eq!(
+            store.load_session("123").await.unwrap(),
+            Some(session.clone())
+        );
+
+        session.history.truncate(1);
+        store.save_session("123", &session).await.unwrap();
+        assert_eq!(store.load_session("123").await.unwrap(), Some(session));
+        assert_eq!(store.load_session("456").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_threads() {
+        let store = Store::in_memory().await.unwrap();
+        assert_eq!(store.user_thread("123").await.unwrap(), None);
+
+        store.add_thread("999", "123").await.unwrap();
+        assert_eq!(
+            store.user_thread("123").await.unwrap(),
+            Some("999".to_string())
+        );
+        assert_eq!(
+            store.thread_owner("999").await.unwrap(),
+            Some("123".to_string())
+        );
+        assert_eq!(store.thread_owner("123").await.unwrap(), None);
+
+        // Thread sessions don't count as users in their own right
+        store
+            .save_session("thread:999", &Session::default())
+            .await
+            .unwrap();
+        store
+            .save_session("456", &Session::default())
+            .await
+            .unwrap();
+        assert_eq!(store.session_user_ids().await.unwrap(), vec!["123", "456"]);
+    }
+
+    #[tokio::test]
+    async fn test_usage_totals() {
+        let store = Store::in_memory().await.unwrap();
+        let usage = Usage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+        };
+
+        store
+            .record_usage("123", UsageKind::Conversation, usage)
+            .await
+            .unwrap();
+        store
+            .record_usage("123", UsageKind::Conversation, usage)
+            .await
+            .unwrap();
+        store
+            .record_usage("456", UsageKind::Cases, usage)
+            .await
+            .unwrap();
+
+        let totals = store.usage_totals(None).await.unwrap();
+        assert_eq!(
+            totals,
+            vec![
+                UsageTotal {
+                    user_id: "123".to_string(),
+                    kind: UsageKind::Conversation,
+                    requests: 2,
+                    usage: Usage {
+                        prompt_tokens: 20,
+                        completion_tokens: 10
+                    },
+                },
+                UsageTotal {
+                    user_id: "456".to_string(),
+                    kind: UsageKind::Cases,
+                    requests: 1,
+                    usage,
+                },
+            ]
+        );
+
+        let totals = store.usage_totals(Some("456")).await.unwrap();
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].user_id, "456");
+    }
 }
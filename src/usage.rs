@@ -0,0 +1,145 @@
+use strum::{Display, EnumString};
+
+use crate::{gpt::Usage, store::UsageTotal};
+
+/// Default price per 1K prompt tokens, in USD (gpt-3.5-turbo)
+pub const DEFAULT_PROMPT_PRICE: f64 = 0.0015;
+/// Default price per 1K completion tokens, in USD (gpt-3.5-turbo)
+pub const DEFAULT_COMPLETION_PRICE: f64 = 0.002;
+
+/// What tokens were spent on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, sqlx::Type)]
+#[strum(serialize_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum UsageKind {
+    /// Replies in the ongoing conversation
+    Conversation,
+    /// Corrections of the user's messages
+    Correction,
+    Ask,
+    Define,
+    Cases,
+    Example,
+    /// Translations of replies, from the Translate button
+    Translate,
+    /// Explanations of corrections, from the Explain button
+    Explain,
+}
+
+/// What the backend charges for tokens, per 1K
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Self {
+            prompt: DEFAULT_PROMPT_PRICE,
+            completion: DEFAULT_COMPLETION_PRICE,
+        }
+    }
+}
+
+impl Pricing {
+    pub fn cost(&self, usage: Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion)
+            / 1000.0
+    }
+}
+
+/// Formats usage totals as a table, with a grand total at the bottom
+pub fn report(totals: &[UsageTotal], pricing: &Pricing) -> String {
+    if totals.is_empty() {
+        return "No usage recorded yet.".to_string();
+    }
+
+    let mut buf = format!(
+        "{:<20} {:<12} {:>8} {:>10} {:>10} {:>9}\n",
+        "user", "kind", "requests", "prompt", "completion", "cost ($)"
+    );
+
+    let mut requests = 0;
+    let mut usage = Usage::default();
+
+    for total in totals {
+        buf.push_str(&format!(
+            "{:<20} {:<12} {:>8} {:>10} {:>10} {:>9.4}\n",
+            total.user_id,
+            total.kind,
+            total.requests,
+            total.usage.prompt_tokens,
+            total.usage.completion_tokens,
+            pricing.cost(total.usage)
+        ));
+
+        requests += total.requests;
+        usage += total.usage;
+    }
+
+    buf.push_str(&format!(
+        "{:<20} {:<12} {:>8} {:>10} {:>10} {:>9.4}",
+        "total",
+        "",
+        requests,
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        pricing.cost(usage)
+    ));
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost() {
+        let pricing = Pricing {
+            prompt: 0.5,
+            completion: 2.0,
+        };
+        let usage = Usage {
+            prompt_tokens: 2000,
+            completion_tokens: 500,
+        };
+
+        assert_eq!(pricing.cost(usage), 2.0);
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(report(&[], &Pricing::default()), "No usage recorded yet.");
+
+        let totals = vec![
+            UsageTotal {
+                user_id: "123".to_string(),
+                kind: UsageKind::Conversation,
+                requests: 2,
+                usage: Usage {
+                    prompt_tokens: 1000,
+                    completion_tokens: 0,
+                },
+            },
+            UsageTotal {
+                user_id: "456".to_string(),
+                kind: UsageKind::Define,
+                requests: 1,
+                usage: Usage {
+                    prompt_tokens: 0,
+                    completion_tokens: 1000,
+                },
+            },
+        ];
+        let report = report(&totals, &Pricing::default());
+        let lines: Vec<_> = report.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("123") && lines[1].contains("conversation"));
+        assert!(lines[2].ends_with("0.0020"));
+        assert!(lines[3].starts_with("total") && lines[3].ends_with("0.0035"));
+    }
+}